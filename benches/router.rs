@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+
+use immortal_http::router::Router;
+
+/// A router with `count` routes spread over resources, a quarter of them with parameters
+fn router(count: usize) -> Router {
+    let mut router = Router::new();
+    for n in 0..count {
+        let route = match n % 4 {
+            0 => format!("/api/v1/resource{n}/:id"),
+            1 => format!("/api/v1/resource{n}/list"),
+            2 => format!("/api/v2/resource{n}"),
+            _ => format!("/static/section{n}/*path"),
+        };
+        router.register("GET", &route, |_| {}).unwrap();
+    }
+    router
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for count in [10, 100, 400, 1000] {
+        let router = router(count);
+        group.bench_with_input(BenchmarkId::new("static", count), &router, |b, router| {
+            b.iter(|| router.find("GET", black_box("/api/v1/resource5/list")))
+        });
+        group.bench_with_input(BenchmarkId::new("param", count), &router, |b, router| {
+            b.iter(|| router.find("GET", black_box("/api/v1/resource4/42")))
+        });
+        group.bench_with_input(BenchmarkId::new("catch_all", count), &router, |b, router| {
+            b.iter(|| router.find("GET", black_box("/static/section3/css/site/main.css")))
+        });
+        group.bench_with_input(BenchmarkId::new("miss", count), &router, |b, router| {
+            b.iter(|| router.find("GET", black_box("/api/v3/missing")))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
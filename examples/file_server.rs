@@ -1,12 +1,10 @@
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::BufReader;
 use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::RwLock;
 
 use immortal_http::{Immortal, Context};
 
-use lazy_static::lazy_static;
 use clap::Parser;
 
 #[derive(Parser)]
@@ -44,25 +42,25 @@ fn main() {
         }
     };
 
-    *WEB_ROOT.write().unwrap() = web_root;
-
     let socket_addr = SocketAddr::from((opts.ip, opts.port));
 
     let mut immortal = Immortal::new();
     immortal.disable_sessions();
-    immortal.fallback(web_server);
+    immortal.manage(WebRoot(web_root));
+    immortal.register("GET", "/*path", web_server).unwrap();
+    immortal.register("HEAD", "/*path", web_server).unwrap();
+    immortal.fallback(four_oh_four);
 
     if let Err(e) = immortal.listen_with(socket_addr, 4) {
         panic!("Fatal error: {e}");
     }
 }
 
-lazy_static! {
-    static ref WEB_ROOT: RwLock<PathBuf> = RwLock::new(PathBuf::new());
-}
+/// The directory files are served from
+struct WebRoot(PathBuf);
 
 fn web_server(ctx: &mut Context) {
-    let mut path = WEB_ROOT.read().unwrap().clone();
+    let mut path = ctx.state::<WebRoot>().0.clone();
     let mut document = ctx.request().document.to_string();
     document = collapse_chr(&document, '/');
     document = collapse_chr(&document, '.');
@@ -91,18 +89,15 @@ fn web_server(ctx: &mut Context) {
             }
         };
 
-        let mut file = BufReader::new(file);
-        let mut body = vec![];
-        match file.read_to_end(&mut body) {
-            Ok(_) => (),
+        let length = match file.metadata() {
+            Ok(metadata) => usize::try_from(metadata.len()).ok(),
             Err(why) => {
                 eprintln!("ERROR: {why}");
-                body.clear();
                 four_oh_four(ctx);
                 return;
             },
-        }
-        ctx.response_mut().body.extend_from_slice(&body);
+        };
+        ctx.response_mut().stream_reader(BufReader::new(file), length);
 
         ctx.response_mut().headers.insert("Content-Type", match path.extension() {
             Some(ext) => match ext.as_encoded_bytes() {
@@ -50,7 +50,7 @@ fn main() {
                 ctx.response_mut().body.append(&mut b"<p>Click <a href=\"/secret\">HERE</a> to see the secret</p>".to_vec());
             },
         };
-    });
+    }).unwrap();
 
     immortal.register("GET", "/login", |ctx| {
         if is_logged_in(ctx) {
@@ -77,7 +77,7 @@ fn main() {
                 clear_message(ctx);
             },
         };
-    });
+    }).unwrap();
 
     immortal.register("POST", "/login", |ctx| {
         if is_logged_in(ctx) {
@@ -97,7 +97,7 @@ fn main() {
 
         set_message(ctx, "Failed to log in");
         ctx.redirect("/login");
-    });
+    }).unwrap();
 
     immortal.register("GET", "/logout", |ctx| {
         if is_logged_in(ctx) {
@@ -107,11 +107,11 @@ fn main() {
             set_message(ctx, "Not logged in");
         }
         ctx.redirect("/login");
-    });
+    }).unwrap();
 
     immortal.register("GET", "/secret", |ctx| {
         ctx.response_mut().body.append("<h1>This is the super secret page</h1>".as_bytes().to_vec().as_mut());
-    });
+    }).unwrap();
 
     if let Err(e) = immortal.listen(([127, 0, 0, 1], 7777)) {
         panic!("{}", e);
@@ -0,0 +1,337 @@
+
+use std::env;
+use std::error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::framing::Limits;
+
+/// The settings that can be loaded from TOML or the environment, sections are separated from
+/// their key by a dot
+///
+/// In the environment they are upper cased, with the dot replaced by an underscore and prefixed
+/// with `IMMORTAL_`, such as `IMMORTAL_TIMEOUTS_KEEP_ALIVE`. Durations are given in seconds.
+const KEYS: [&str; 17] = [
+    "threads",
+    "read_buffer_size",
+    "server_banner",
+    "limits.max_header_size",
+    "limits.max_body_size",
+    "limits.max_connections",
+    "limits.max_requests_per_connection",
+    "timeouts.keep_alive",
+    "timeouts.header_read",
+    "timeouts.body_read",
+    "timeouts.write",
+    "timeouts.shutdown",
+    "sessions.enabled",
+    "sessions.duration",
+    "sessions.inactive_duration",
+    "sessions.prune_rate",
+    "metrics.path",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The TOML source could not be parsed
+    #[cfg(feature = "toml")]
+    Toml(toml::de::Error),
+    /// The setting does not exist
+    UnknownKey(String),
+    /// The value is of the wrong type or out of range for the setting
+    InvalidValue { key: String, value: String },
+}
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl error::Error for ConfigError {}
+
+/// Every tunable of the server, passed to `Immortal::with_config`
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How many threads serve connections, 0 for as many as the system has available for
+    /// parallelism. Ignored without the `threading` feature.
+    pub threads: usize,
+    /// How many bytes are read from a connection at a time
+    pub read_buffer_size: usize,
+    /// Size limits for request heads and bodies
+    pub limits: Limits,
+    /// How many connections may be served or queued at once, 0 for no limit
+    pub max_connections: usize,
+    /// How many requests may be served over a single connection, 0 for no limit
+    pub max_requests_per_connection: usize,
+    /// How long a persistent connection may sit idle waiting for its next request
+    pub keep_alive_timeout: Duration,
+    /// How long a client has to send a complete request head
+    pub header_read_timeout: Duration,
+    /// How long a client has to send a complete request body once the head has arrived
+    pub body_read_timeout: Duration,
+    /// How long a single write of the response may block for
+    pub write_timeout: Duration,
+    /// How long in-flight requests are given to finish during a shutdown
+    pub shutdown_timeout: Duration,
+    /// Headers every response starts out with, before middleware runs
+    pub default_headers: Vec<(String, String)>,
+    /// Sent in the `Server` header of every response if set
+    pub server_banner: Option<String>,
+    /// Whether sessions are enabled
+    pub sessions: bool,
+    /// The maximum duration that a session may be allowed to persist for regardless of
+    /// inactivity
+    pub session_duration: Duration,
+    /// The duration a session will persist for if inactive
+    pub session_inactive_duration: Duration,
+    /// How often the session store is pruned
+    pub session_prune_rate: Duration,
+    /// Where the metrics are served, they are not served if unset
+    pub metrics_path: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            read_buffer_size: 4096,
+            limits: Limits::default(),
+            max_connections: 1024,
+            max_requests_per_connection: 100,
+            keep_alive_timeout: Duration::from_secs(5),
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(30),
+            default_headers: vec![("Content-Type".to_string(), "text/html".to_string())],
+            server_banner: None,
+            sessions: false,
+            session_duration: Duration::from_secs(12 * 3600),    // 12 hours
+            session_inactive_duration: Duration::from_secs(3600), //  1 hour
+            session_prune_rate: Duration::from_secs(60),          //  1 min
+            metrics_path: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::new()
+    }
+}
+
+/// Builds a `ServerConfig`, starting from the defaults
+///
+/// Later calls override earlier ones, so settings can be layered:
+/// `ServerConfig::builder().toml(&file)?.env()?.threads(4).build()`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.config.read_buffer_size = size;
+        self
+    }
+
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.config.limits.max_header_size = size;
+        self
+    }
+
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.config.limits.max_body_size = size;
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.config.max_requests_per_connection = max;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, duration: Duration) -> Self {
+        self.config.keep_alive_timeout = duration;
+        self
+    }
+
+    pub fn header_read_timeout(mut self, duration: Duration) -> Self {
+        self.config.header_read_timeout = duration;
+        self
+    }
+
+    pub fn body_read_timeout(mut self, duration: Duration) -> Self {
+        self.config.body_read_timeout = duration;
+        self
+    }
+
+    pub fn write_timeout(mut self, duration: Duration) -> Self {
+        self.config.write_timeout = duration;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, duration: Duration) -> Self {
+        self.config.shutdown_timeout = duration;
+        self
+    }
+
+    /// Sets a header every response starts out with, replacing any default of the same name
+    pub fn default_header(mut self, name: &str, value: &str) -> Self {
+        self.config.default_headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.config.default_headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Removes every default header, including `Content-Type`
+    pub fn clear_default_headers(mut self) -> Self {
+        self.config.default_headers.clear();
+        self
+    }
+
+    pub fn server_banner(mut self, banner: &str) -> Self {
+        self.config.server_banner = Some(banner.to_string());
+        self
+    }
+
+    pub fn sessions(mut self, enabled: bool) -> Self {
+        self.config.sessions = enabled;
+        self
+    }
+
+    pub fn session_duration(mut self, duration: Duration) -> Self {
+        self.config.session_duration = duration;
+        self
+    }
+
+    pub fn session_inactive_duration(mut self, duration: Duration) -> Self {
+        self.config.session_inactive_duration = duration;
+        self
+    }
+
+    pub fn session_prune_rate(mut self, duration: Duration) -> Self {
+        self.config.session_prune_rate = duration;
+        self
+    }
+
+    pub fn metrics_path(mut self, path: &str) -> Self {
+        self.config.metrics_path = Some(path.to_string());
+        self
+    }
+
+    /// Applies the settings in a TOML document, default headers go in a `[headers]` table
+    ///
+    /// ```toml
+    /// threads = 4
+    /// server_banner = "immortal"
+    ///
+    /// [timeouts]
+    /// keep_alive = 2.5
+    ///
+    /// [headers]
+    /// X-Frame-Options = "DENY"
+    /// ```
+    #[cfg(feature = "toml")]
+    pub fn toml(mut self, source: &str) -> Result<Self, ConfigError> {
+        let table = source.parse::<toml::Table>()
+            .map_err(ConfigError::Toml)?;
+        for (key, value) in &table {
+            match value {
+                toml::Value::Table(section) => {
+                    for (name, value) in section {
+                        self.set(&format!("{key}.{name}"), value)?;
+                    }
+                },
+                value => self.set(key, value)?,
+            }
+        }
+        Ok(self)
+    }
+
+    /// Applies the settings found in `IMMORTAL_` environment variables, see `KEYS`
+    pub fn env(mut self) -> Result<Self, ConfigError> {
+        for key in KEYS {
+            let var = format!("IMMORTAL_{}", key.replace('.', "_").to_uppercase());
+            if let Ok(value) = env::var(&var) {
+                self.apply(key, &value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> ServerConfig {
+        self.config
+    }
+
+    /// Applies a single TOML value
+    #[cfg(feature = "toml")]
+    fn set(&mut self, key: &str, value: &toml::Value) -> Result<(), ConfigError> {
+        match value {
+            toml::Value::String(value) => self.apply(key, value),
+            toml::Value::Integer(value) => self.apply(key, &value.to_string()),
+            toml::Value::Float(value) => self.apply(key, &value.to_string()),
+            toml::Value::Boolean(value) => self.apply(key, &value.to_string()),
+            value => Err(invalid(key, value.type_str())),
+        }
+    }
+
+    /// Parses and applies a single setting
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let config = &mut self.config;
+        match key {
+            "threads" => config.threads = parse(key, value)?,
+            "read_buffer_size" => config.read_buffer_size = parse(key, value)?,
+            "server_banner" => config.server_banner = Some(value.to_string()),
+            "limits.max_header_size" => config.limits.max_header_size = parse(key, value)?,
+            "limits.max_body_size" => config.limits.max_body_size = parse(key, value)?,
+            "limits.max_connections" => config.max_connections = parse(key, value)?,
+            "limits.max_requests_per_connection" => config.max_requests_per_connection = parse(key, value)?,
+            "timeouts.keep_alive" => config.keep_alive_timeout = seconds(key, value)?,
+            "timeouts.header_read" => config.header_read_timeout = seconds(key, value)?,
+            "timeouts.body_read" => config.body_read_timeout = seconds(key, value)?,
+            "timeouts.write" => config.write_timeout = seconds(key, value)?,
+            "timeouts.shutdown" => config.shutdown_timeout = seconds(key, value)?,
+            "sessions.enabled" => config.sessions = parse(key, value)?,
+            "sessions.duration" => config.session_duration = seconds(key, value)?,
+            "sessions.inactive_duration" => config.session_inactive_duration = seconds(key, value)?,
+            "sessions.prune_rate" => config.session_prune_rate = seconds(key, value)?,
+            "metrics.path" => config.metrics_path = Some(value.to_string()),
+            _ => match key.strip_prefix("headers.") {
+                Some(name) => *self = std::mem::take(self).default_header(name, value),
+                None => return Err(ConfigError::UnknownKey(key.to_string())),
+            },
+        }
+        Ok(())
+    }
+}
+
+fn invalid(key: &str, value: impl Display) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.trim().parse::<T>()
+        .map_err(|_| invalid(key, value))
+}
+
+/// Parses a duration given in seconds, which may be fractional
+fn seconds(key: &str, value: &str) -> Result<Duration, ConfigError> {
+    parse::<f64>(key, value)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).map_err(|_| invalid(key, value)))
+}
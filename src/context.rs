@@ -4,6 +4,7 @@ use uuid::Uuid;
 use crate::request::Request;
 use crate::response::Response;
 use crate::session::SessionManager;
+use crate::state::State;
 
 use std::rc::Rc;
 use std::sync::Arc;
@@ -16,6 +17,11 @@ pub struct Context<'req> {
     response: Rc<RefCell<Response<'req>>>,
     pub session_id: Uuid,
     session_manager: Arc<SessionManager>,
+    /// The registered route that matched the request, once the router has matched it
+    pub(crate) route: Option<&'req str>,
+    /// Values captured by the parameters of the matched route
+    pub(crate) params: Vec<(&'req str, &'req str)>,
+    state: Option<Arc<State>>,
 }
 
 #[allow(dead_code)]
@@ -29,9 +35,35 @@ impl<'req> Context<'req> {
             response,
             session_id,
             session_manager,
+            route: None,
+            params: Vec::new(),
+            state: None,
         }
     }
 
+    /// Gives the context the application state, which the server does for every request
+    ///
+    /// Handlers can be tested on their own by building a context with substitute state.
+    pub fn with_state(mut self, state: Arc<State>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Returns the value of type `T` managed by the server, see `Immortal::manage`
+    ///
+    /// Panics if no value of the type is managed, which answers the request with a `500`.
+    pub fn state<T>(&self) -> &T where T: Send + Sync + 'static {
+        match self.try_state::<T>() {
+            Some(value) => value,
+            None => panic!("no state of type {} is managed", std::any::type_name::<T>()),
+        }
+    }
+
+    /// Returns the value of type `T` managed by the server, if there is one
+    pub fn try_state<T>(&self) -> Option<&T> where T: Send + Sync + 'static {
+        self.state.as_ref()?.get::<T>()
+    }
+
     /// Borrow the reference to the request.
     pub fn request(&self) -> Ref<Request<'req>> {
         self.request.borrow()
@@ -44,6 +76,15 @@ impl<'req> Context<'req> {
         self.request.borrow_mut()
     }
 
+    /// Returns the value captured by the route parameter `name`, such as `id` in `/users/:id`
+    ///
+    /// The value is not URL decoded, like query parameters.
+    pub fn param(&self, name: &str) -> Option<&'req str> {
+        self.params.iter()
+            .find(|(key, _value)| *key == name)
+            .map(|(_key, value)| *value)
+    }
+
     /// Borrow the response that is to be sent back to the client.
     pub fn response(&self) -> Ref<Response<'req>> {
         self.response.borrow()
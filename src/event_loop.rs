@@ -0,0 +1,379 @@
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::time::Instant;
+
+use debug_print::debug_eprintln;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use crate::framing::{Frame, Framer, FramingError};
+use crate::shutdown::ConnectionGuard;
+use crate::transport::{Captured, Listener, PeerAddr, Transport};
+use crate::{Immortal, ImmortalError, ReadError};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// The most reads made from one connection before the others get a turn
+const MAX_READS_PER_EVENT: usize = 16;
+
+/// A socket that can be polled by the event loop
+pub(crate) trait Pollable: AsRawFd {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Pollable for TcpListener {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Pollable for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Pollable for UnixListener {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Pollable for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// A connection being served by the event loop
+struct Connection<S> {
+    stream: S,
+    peer_addr: Option<PeerAddr>,
+    /// Bytes read that have not been answered yet
+    buf: Vec<u8>,
+    /// Progress framing the request at the start of `buf`
+    framer: Framer,
+    /// The request at the start of `buf` once it has arrived in full, or why it never will
+    framed: Option<Result<Frame, FramingError>>,
+    /// Response bytes that have not been written yet
+    out: Vec<u8>,
+    served: usize,
+    head_deadline: Option<Instant>,
+    body_deadline: Option<Instant>,
+    /// When the connection times out unless it makes progress
+    deadline: Instant,
+    /// The connection is closed once `out` has been written
+    closing: bool,
+    /// The client has stopped sending
+    eof: bool,
+    _guard: ConnectionGuard,
+}
+
+impl<S: Transport + Pollable> Connection<S> {
+    /// Makes as much progress as the socket allows, returns false once the connection is done
+    ///
+    /// Requests are answered one at a time, the next one is not read until the response to the
+    /// previous one has been written. At most `MAX_READS_PER_EVENT` reads are made before the
+    /// connection is polled again, so a fast client cannot keep the others waiting.
+    fn ready(&mut self, immortal: &Immortal, registry: &Registry, token: Token) -> bool {
+        let mut reads = MAX_READS_PER_EVENT;
+        loop {
+            if !self.flush(immortal) {
+                return false;
+            }
+            if !self.out.is_empty() {
+                return true;
+            }
+            if self.closing {
+                return false;
+            }
+
+            self.fill(immortal, &mut reads);
+            self.process(immortal);
+            if self.out.is_empty() && !self.closing {
+                if reads == 0 && !self.eof {
+                    // the socket may still be readable, which is only reported again once the
+                    // interest is re-armed
+                    let interest = Interest::READABLE | Interest::WRITABLE;
+                    return registry.reregister(&mut SourceFd(&self.stream.as_raw_fd()), token, interest).is_ok();
+                }
+                // a partial request is dropped if the client stops sending
+                return !self.eof;
+            }
+        }
+    }
+
+    /// Reads until the request at the start of the buffer has arrived in full, the socket would
+    /// block or `reads` runs out
+    ///
+    /// The buffer never holds much more than a single request, since framing fails once a
+    /// request exceeds the limits.
+    fn fill(&mut self, immortal: &Immortal, reads: &mut usize) {
+        let mut chunk = vec![0u8; immortal.read_buffer_size];
+        loop {
+            if self.framed.is_none() {
+                self.framed = self.framer.frame(&self.buf, &immortal.limits).transpose();
+            }
+            if self.framed.is_some() || self.eof || *reads == 0 {
+                return;
+            }
+
+            *reads -= 1;
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(sz) => self.buf.extend_from_slice(&chunk[..sz]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_e) => {
+                    debug_eprintln!("{}", _e);
+                    self.eof = true;
+                    self.closing = true;
+                },
+            }
+        }
+    }
+
+    /// Answers the next request in the buffer if it has arrived in full, otherwise works out how
+    /// long the client has left to send it
+    fn process(&mut self, immortal: &Immortal) {
+        let mut captured = Captured::new(self.peer_addr.clone());
+        match self.framed.take() {
+            Some(Ok(frame)) => {
+                self.served += 1;
+                let keep_alive = crate::serve_request(&mut captured, &self.buf, &frame, self.peer_addr.as_ref(), None, self.served, immortal);
+                self.buf.drain(..frame.len());
+                self.head_deadline = None;
+                self.body_deadline = None;
+                self.closing |= !keep_alive;
+            },
+            None => {
+                // the body deadline is only unset the first time round with a complete head
+                if let (Some(head_len), None) = (self.framer.head_len(), self.body_deadline) {
+                    let proceed = crate::answer_expectation(&mut captured, &self.buf[..head_len], self.peer_addr.as_ref(), None, immortal);
+                    self.closing |= !proceed;
+                }
+
+                // subsequent requests on a persistent connection may only idle for so long
+                let idle_timeout = match self.served {
+                    0 => immortal.header_read_timeout,
+                    _ => immortal.keep_alive_timeout,
+                };
+                let timeout = crate::read_timeout(&self.buf, &self.framer, idle_timeout, &mut self.head_deadline, &mut self.body_deadline, immortal);
+                self.deadline = Instant::now() + timeout;
+            },
+            Some(Err(e)) => {
+                debug_eprintln!("{}", e);
+                crate::write_status(&mut captured, ReadError::from(e).status_code(), immortal);
+                self.closing = true;
+            },
+        }
+
+        if !captured.out.is_empty() {
+            self.out = captured.out;
+            self.deadline = Instant::now() + immortal.write_timeout;
+        }
+    }
+
+    /// Writes until the socket would block, returns false if the client can no longer be
+    /// written to
+    fn flush(&mut self, immortal: &Immortal) -> bool {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return false,
+                Ok(sz) => {
+                    self.out.drain(..sz);
+                    self.deadline = Instant::now() + immortal.write_timeout;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_e) => {
+                    debug_eprintln!("{}", _e);
+                    return false;
+                },
+            }
+        }
+        true
+    }
+
+    /// Handles the deadline passing, returns false once the connection is done
+    fn time_out(&mut self, immortal: &Immortal) -> bool {
+        // clients that stopped reading their response or never started a request are dropped
+        if !self.out.is_empty() || self.buf.is_empty() {
+            return false;
+        }
+        let mut captured = Captured::new(self.peer_addr.clone());
+        crate::write_status(&mut captured, ReadError::TimedOut.status_code(), immortal);
+        self.out = captured.out;
+        self.closing = true;
+        self.deadline = Instant::now() + immortal.write_timeout;
+        self.flush(immortal) && !self.out.is_empty()
+    }
+
+    /// True if the connection is waiting for a request rather than writing a response
+    fn is_idle(&self) -> bool {
+        self.out.is_empty()
+    }
+
+    fn close(mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut SourceFd(&self.stream.as_raw_fd()));
+        self.stream.shutdown();
+    }
+}
+
+/// Serves connections from `listener` on the current thread until the server is shut down,
+/// interleaving them as their sockets become ready
+pub(crate) fn serve<L>(immortal: &Immortal, listener: L) -> Result<(), ImmortalError<'static>> where
+    L: Listener + Pollable,
+    L::Stream: Pollable,
+{
+    listener.set_nonblocking(true)
+        .map_err(ImmortalError::Io)?;
+    let mut poll = Poll::new()
+        .map_err(ImmortalError::Io)?;
+    poll.registry()
+        .register(&mut SourceFd(&listener.as_raw_fd()), LISTENER, Interest::READABLE)
+        .map_err(ImmortalError::Io)?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).map_err(ImmortalError::Io)?);
+    immortal.shutdown.set_waker(Some(Box::new(move || {
+        let _ = waker.wake();
+    })));
+
+    let mut events = Events::with_capacity(1024);
+    let mut connections: HashMap<Token, Connection<L::Stream>> = HashMap::new();
+    let mut next_token: usize = WAKER.0 + 1;
+    let mut drain_deadline: Option<Instant> = None;
+    let result = 'serve: loop {
+        if immortal.shutdown.is_shutdown() && drain_deadline.is_none() {
+            let _ = poll.registry().deregister(&mut SourceFd(&listener.as_raw_fd()));
+            drain_deadline = Some(Instant::now() + immortal.shutdown_timeout);
+        }
+        if let Some(deadline) = drain_deadline {
+            // connections waiting for a request are closed straight away, the others are given
+            // until the deadline to finish
+            for token in matching(&connections, Connection::is_idle) {
+                connections.remove(&token).unwrap().close(poll.registry());
+            }
+            if connections.is_empty() || Instant::now() >= deadline {
+                break Ok(());
+            }
+        }
+
+        let timeout = connections.values()
+            .map(|connection| connection.deadline)
+            .chain(drain_deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break Err(ImmortalError::Io(e));
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {},
+                LISTENER => {
+                    if let Err(e) = accept(immortal, &listener, poll.registry(), &mut connections, &mut next_token) {
+                        break 'serve Err(ImmortalError::AcceptError(e));
+                    }
+                },
+                token => {
+                    let done = match connections.get_mut(&token) {
+                        Some(connection) => !connection.ready(immortal, poll.registry(), token),
+                        None => continue,
+                    };
+                    if done {
+                        connections.remove(&token).unwrap().close(poll.registry());
+                    }
+                },
+            }
+        }
+
+        let now = Instant::now();
+        for token in matching(&connections, |connection| connection.deadline <= now) {
+            let mut connection = connections.remove(&token).unwrap();
+            if connection.time_out(immortal) {
+                connections.insert(token, connection);
+            } else {
+                connection.close(poll.registry());
+            }
+        }
+    };
+
+    for (_, connection) in connections.drain() {
+        connection.close(poll.registry());
+    }
+    immortal.shutdown.set_waker(None);
+    result
+}
+
+/// Accepts connections until the listener would block
+fn accept<L>(
+    immortal: &Immortal,
+    listener: &L,
+    registry: &Registry,
+    connections: &mut HashMap<Token, Connection<L::Stream>>,
+    next_token: &mut usize,
+) -> io::Result<()> where
+    L: Listener,
+    L::Stream: Pollable,
+{
+    loop {
+        let stream = match listener.accept_stream() {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted) => continue,
+            Err(e) => return Err(e),
+        };
+        if immortal.shutdown.is_shutdown() {
+            return Ok(());
+        }
+        if immortal.at_capacity() {
+            crate::shed_connection(stream, immortal);
+            continue;
+        }
+        if stream.set_nonblocking(true).is_err() {
+            continue;
+        }
+
+        let token = Token(*next_token);
+        *next_token += 1;
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        if registry.register(&mut SourceFd(&stream.as_raw_fd()), token, interest).is_err() {
+            continue;
+        }
+        connections.insert(token, Connection {
+            peer_addr: stream.peer_addr(),
+            stream,
+            buf: Vec::with_capacity(4096),
+            framer: Framer::default(),
+            framed: None,
+            out: Vec::new(),
+            served: 0,
+            head_deadline: None,
+            body_deadline: None,
+            deadline: Instant::now() + immortal.header_read_timeout,
+            closing: false,
+            eof: false,
+            // the event loop closes its own connections during a shutdown
+            _guard: immortal.connections.register(Box::new(|| {})),
+        });
+    }
+}
+
+/// The tokens of the connections matching `predicate`
+fn matching<S, P>(connections: &HashMap<Token, Connection<S>>, predicate: P) -> Vec<Token> where
+    P: Fn(&Connection<S>) -> bool,
+{
+    connections.iter()
+        .filter(|(_, connection)| predicate(connection))
+        .map(|(token, _)| *token)
+        .collect()
+}
@@ -0,0 +1,396 @@
+
+use std::fmt::Display;
+use std::str;
+use std::error;
+
+/// The maximum size of the extensions on a single chunk-size line
+pub const MAX_CHUNK_EXTENSION_SIZE: usize = 256;
+
+/// The amount of wire bytes each chunk may take up on top of its data, covering the chunk-size
+/// line and the crlf after the data
+pub const CHUNK_OVERHEAD: usize = 32;
+
+/// The longest chunk-size line accepted, the size itself is at most 16 hex digits
+const MAX_CHUNK_LINE_SIZE: usize = 32 + MAX_CHUNK_EXTENSION_SIZE;
+
+/// Size limits applied while framing a request out of the connection buffer
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The maximum size of the request line and headers, including the terminating empty line
+    pub max_header_size: usize,
+    /// The maximum size of a request body
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_size: 8 * 1024,   //  8 KiB of request line and headers
+            max_body_size: 1024 * 1024,  //  1 MiB of body
+        }
+    }
+}
+
+/// Describes where a complete request sits inside the connection buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Length of the request head, including the terminating empty line
+    pub head_len: usize,
+    /// Length of the request body that directly follows the head, as sent on the wire
+    pub body_len: usize,
+    /// The decoded body if the request was sent with `Transfer-Encoding: chunked`
+    pub decoded_body: Option<Vec<u8>>,
+}
+
+impl Frame {
+    /// The amount of bytes this request occupies in the buffer
+    pub fn len(&self) -> usize {
+        self.head_len + self.body_len
+    }
+
+    /// Returns true if the frame occupies no bytes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug)]
+pub enum FramingError {
+    /// The request line and headers exceed `Limits::max_header_size`
+    HeadTooLarge { limit: usize },
+    /// The declared body exceeds `Limits::max_body_size`
+    BodyTooLarge { limit: usize, declared: usize },
+    /// The `Content-Length` header is not a number, or there are conflicting values
+    ContentLengthInvalid,
+    /// Both `Content-Length` and `Transfer-Encoding` were sent, so the body length is ambiguous
+    ContentLengthWithTransferEncoding,
+    /// A transfer coding other than `chunked` was requested
+    TransferEncodingUnsupported,
+    /// The chunked body does not follow the chunked transfer coding
+    ChunkedMalformed,
+    /// The extensions on a chunk-size line exceed `MAX_CHUNK_EXTENSION_SIZE`
+    ChunkExtensionTooLarge { limit: usize },
+    /// The chunked body takes up more bytes on the wire than `Limits::max_body_size` and
+    /// `CHUNK_OVERHEAD` per chunk allow for
+    ChunkedTooLarge { limit: usize },
+}
+impl Display for FramingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl error::Error for FramingError {}
+
+impl FramingError {
+    /// The HTTP status code that the client should be answered with
+    pub fn status_code(&self) -> &'static str {
+        match self {
+            FramingError::HeadTooLarge { .. } => "431",
+            FramingError::BodyTooLarge { .. } => "413",
+            FramingError::ContentLengthInvalid => "400",
+            FramingError::ContentLengthWithTransferEncoding => "400",
+            FramingError::TransferEncodingUnsupported => "501",
+            FramingError::ChunkedMalformed => "400",
+            FramingError::ChunkExtensionTooLarge { .. } => "413",
+            FramingError::ChunkedTooLarge { .. } => "413",
+        }
+    }
+}
+
+/// Tries to find a complete request at the start of `buf`.
+///
+/// Returns `Ok(None)` if more bytes are needed before the request is complete, or an error if
+/// the request can never be completed within the limits. Use a `Framer` to frame a buffer that is
+/// still filling up.
+pub fn frame(buf: &[u8], limits: &Limits) -> Result<Option<Frame>, FramingError> {
+    Framer::default().frame(buf, limits)
+}
+
+/// Frames the request at the start of a connection buffer as it fills up
+///
+/// The framer remembers how far it got between calls, so bytes that were already looked at are
+/// not walked again after every read. Once a frame is returned it starts over, the caller drains
+/// the frame from the buffer before framing the next request.
+#[derive(Debug, Default)]
+pub struct Framer {
+    /// How far the search for the end of the head got
+    scanned: usize,
+    /// Length of the head once it has arrived in full
+    head_len: Option<usize>,
+    /// How the body is delimited, once the head has been read
+    body: Option<Body>,
+}
+
+impl Framer {
+    /// Tries to find a complete request at the start of `buf`, carrying on from the previous call.
+    ///
+    /// `buf` must hold the same bytes it did last time, with more appended to it.
+    pub fn frame(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<Frame>, FramingError> {
+        let head_len = match self.find_head(buf) {
+            None if buf.len() > limits.max_header_size => {
+                return Err(FramingError::HeadTooLarge { limit: limits.max_header_size });
+            },
+            None => return Ok(None),
+            Some(len) => len,
+        };
+
+        if head_len > limits.max_header_size {
+            return Err(FramingError::HeadTooLarge { limit: limits.max_header_size });
+        }
+
+        let mut body = match self.body.take() {
+            Some(body) => body,
+            None => Body::from_head(&buf[..head_len], limits)?,
+        };
+        let body_len = match body.walk(&buf[head_len..], limits)? {
+            None => {
+                self.body = Some(body);
+                return Ok(None);
+            },
+            Some(len) => len,
+        };
+        let decoded_body = match body {
+            Body::Length(_) => None,
+            Body::Chunked(chunked) => Some(chunked.decoded),
+        };
+
+        *self = Framer::default();
+        Ok(Some(Frame { head_len, body_len, decoded_body }))
+    }
+
+    /// Length of the request head, once it has arrived in full
+    pub fn head_len(&self) -> Option<usize> {
+        self.head_len
+    }
+
+    /// Searches the bytes that arrived since the last call for the end of the head
+    fn find_head(&mut self, buf: &[u8]) -> Option<usize> {
+        if self.head_len.is_some() {
+            return self.head_len;
+        }
+
+        let mut start = 0;
+        while buf[start..].starts_with(b"\r\n") {
+            start += 2;
+        }
+
+        // the end of the head may straddle the bytes that were already searched
+        let from = self.scanned.max(start);
+        self.head_len = buf[from..].windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|idx| from + idx + 4);
+        self.scanned = buf.len().saturating_sub(3).max(start);
+        self.head_len
+    }
+}
+
+/// How the body of a request is delimited
+#[derive(Debug)]
+enum Body {
+    /// The body is `Content-Length` bytes long, or empty if there is no such header
+    Length(usize),
+    /// The body is sent with `Transfer-Encoding: chunked`
+    Chunked(Chunked),
+}
+
+impl Body {
+    fn from_head(head: &[u8], limits: &Limits) -> Result<Self, FramingError> {
+        let content_length = content_length(head)?;
+
+        if is_chunked(head)? {
+            if content_length.is_some() {
+                return Err(FramingError::ContentLengthWithTransferEncoding);
+            }
+            return Ok(Body::Chunked(Chunked::default()));
+        }
+
+        let body_len = content_length.unwrap_or(0);
+        if body_len > limits.max_body_size {
+            return Err(FramingError::BodyTooLarge { limit: limits.max_body_size, declared: body_len });
+        }
+        Ok(Body::Length(body_len))
+    }
+
+    /// Returns the amount of bytes the body occupies on the wire once it is complete in `buf`,
+    /// which starts right after the head
+    fn walk(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<usize>, FramingError> {
+        match self {
+            Body::Length(len) if buf.len() < *len => Ok(None),
+            Body::Length(len) => Ok(Some(*len)),
+            Body::Chunked(chunked) => chunked.walk(buf, limits),
+        }
+    }
+}
+
+/// Progress through a chunked body
+#[derive(Debug, Default)]
+struct Chunked {
+    /// Offset of the next line to read, relative to the start of the body
+    pos: usize,
+    /// The data of the chunks read so far
+    decoded: Vec<u8>,
+    /// The amount of chunks read so far
+    chunks: usize,
+    /// Offset of the trailer section, once the last chunk has been read
+    trailer_start: Option<usize>,
+}
+
+impl Chunked {
+    /// Walks the chunked body at the start of `buf` from where the last call left off, decoding
+    /// the chunk data.
+    ///
+    /// Chunk extensions are ignored and the trailer section is consumed but discarded. Returns the
+    /// amount of bytes the chunked body occupies on the wire, or `Ok(None)` if more bytes are
+    /// needed.
+    ///
+    /// Besides the decoded size, the framing around the chunks is capped so that a body sent as
+    /// many tiny chunks cannot take up much more of the buffer than the body limit.
+    fn walk(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<usize>, FramingError> {
+        while self.trailer_start.is_none() {
+            let too_long = FramingError::ChunkExtensionTooLarge { limit: MAX_CHUNK_EXTENSION_SIZE };
+            let line = match line_at(buf, self.pos, MAX_CHUNK_LINE_SIZE, too_long)? {
+                None => return Ok(None),
+                Some(line) => line,
+            };
+            let data = self.pos + line.len() + 2;
+
+            // everything after a ';' is a chunk extension
+            let (size, extension) = match line.iter().position(|c| *c == b';') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => (line, &[][..]),
+            };
+            if extension.len() > MAX_CHUNK_EXTENSION_SIZE {
+                return Err(FramingError::ChunkExtensionTooLarge { limit: MAX_CHUNK_EXTENSION_SIZE });
+            }
+            let size = parse_chunk_size(size.trim_ascii())?;
+            if size == 0 {
+                self.pos = data;
+                self.trailer_start = Some(data);
+                break;
+            }
+
+            let body_len = self.decoded.len().saturating_add(size);
+            if body_len > limits.max_body_size {
+                return Err(FramingError::BodyTooLarge { limit: limits.max_body_size, declared: body_len });
+            }
+
+            let wire_limit = limits.max_body_size.saturating_add((self.chunks + 1).saturating_mul(CHUNK_OVERHEAD));
+            if data + size + 2 > wire_limit {
+                return Err(FramingError::ChunkedTooLarge { limit: wire_limit });
+            }
+
+            if buf.len() < data + size + 2 {
+                return Ok(None);
+            }
+            if &buf[data + size..data + size + 2] != b"\r\n" {
+                return Err(FramingError::ChunkedMalformed);
+            }
+            self.decoded.extend_from_slice(&buf[data..data + size]);
+            self.chunks += 1;
+            self.pos = data + size + 2;
+        }
+
+        // the trailer section is a list of header fields ended by an empty line
+        let trailer_start = self.trailer_start.unwrap_or(self.pos);
+        loop {
+            let too_long = FramingError::HeadTooLarge { limit: limits.max_header_size };
+            let line = match line_at(buf, self.pos, limits.max_header_size, too_long)? {
+                None => return Ok(None),
+                Some(line) => line,
+            };
+            let next = self.pos + line.len() + 2;
+
+            if line.is_empty() {
+                return Ok(Some(next));
+            }
+            if next - trailer_start > limits.max_header_size {
+                return Err(FramingError::HeadTooLarge { limit: limits.max_header_size });
+            }
+            if !line.contains(&b':') {
+                return Err(FramingError::ChunkedMalformed);
+            }
+            self.pos = next;
+        }
+    }
+}
+
+/// Iterates over the header lines of a request head, skipping the request line
+pub(crate) fn header_lines(head: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    head.split(|c| *c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .skip_while(|line| line.is_empty())
+        .skip(1)
+        .filter_map(|line| str::from_utf8(line).ok())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+/// Reads the `Content-Length` header out of a request head
+///
+/// Repeated headers are tolerated only if they all agree.
+pub(crate) fn content_length(head: &[u8]) -> Result<Option<usize>, FramingError> {
+    let mut length = None;
+    for (key, value) in header_lines(head) {
+        if !key.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        if value.is_empty() || !value.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(FramingError::ContentLengthInvalid);
+        }
+        let value = value.parse::<usize>()
+            .map_err(|_| FramingError::ContentLengthInvalid)?;
+        match length {
+            Some(existing) if existing != value => return Err(FramingError::ContentLengthInvalid),
+            _ => length = Some(value),
+        }
+    }
+    Ok(length)
+}
+
+/// Returns true if the request head declares a chunked body
+///
+/// `chunked` is the only transfer coding that is understood.
+pub(crate) fn is_chunked(head: &[u8]) -> Result<bool, FramingError> {
+    let mut chunked = false;
+    for (key, value) in header_lines(head) {
+        if !key.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        for coding in value.split(',').map(|coding| coding.trim()) {
+            if chunked || !coding.eq_ignore_ascii_case("chunked") {
+                return Err(FramingError::TransferEncodingUnsupported);
+            }
+            chunked = true;
+        }
+    }
+    Ok(chunked)
+}
+
+/// Returns the line starting at `pos` without its crlf, if the whole line is in the buffer
+///
+/// Lines longer than `max_len` fail with `too_long`, without searching further than that.
+fn line_at(
+    buf: &[u8],
+    pos: usize,
+    max_len: usize,
+    too_long: FramingError
+) -> Result<Option<&[u8]>, FramingError> {
+    let rest = buf.get(pos..).unwrap_or_default();
+    let window = &rest[..rest.len().min(max_len + 2)];
+    match window.windows(2).position(|window| window == b"\r\n") {
+        Some(idx) => Ok(Some(&rest[..idx])),
+        None if rest.len() >= max_len + 2 => Err(too_long),
+        None => Ok(None),
+    }
+}
+
+/// Parses the hexadecimal size of a chunk
+fn parse_chunk_size(size: &[u8]) -> Result<usize, FramingError> {
+    if size.is_empty() || !size.iter().all(|c| c.is_ascii_hexdigit()) {
+        return Err(FramingError::ChunkedMalformed);
+    }
+    let size = str::from_utf8(size)
+        .map_err(|_| FramingError::ChunkedMalformed)?;
+    usize::from_str_radix(size, 16)
+        .map_err(|_| FramingError::ChunkedMalformed)
+}
@@ -0,0 +1,1028 @@
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use debug_print::debug_eprintln;
+
+use crate::framing;
+use crate::hpack::{self, Decoder, Field, HpackError};
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown::ConnectionGuard;
+use crate::transport::{PeerAddr, Transport};
+use crate::{dispatch, log, Immortal};
+
+/// The connection preface every HTTP/2 client starts with
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// The largest frame payload the server accepts, which is the smallest a peer may allow
+const MAX_FRAME_SIZE: usize = 16_384;
+/// The flow control window every stream and connection starts with
+const INITIAL_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// How many streams a client may have open at once
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+mod kind {
+    pub const DATA: u8 = 0x0;
+    pub const HEADERS: u8 = 0x1;
+    pub const PRIORITY: u8 = 0x2;
+    pub const RST_STREAM: u8 = 0x3;
+    pub const SETTINGS: u8 = 0x4;
+    pub const PUSH_PROMISE: u8 = 0x5;
+    pub const PING: u8 = 0x6;
+    pub const GOAWAY: u8 = 0x7;
+    pub const WINDOW_UPDATE: u8 = 0x8;
+    pub const CONTINUATION: u8 = 0x9;
+}
+
+mod flag {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+mod setting {
+    pub const ENABLE_PUSH: u16 = 0x2;
+    pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+    pub const MAX_FRAME_SIZE: u16 = 0x5;
+    pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+}
+
+mod code {
+    pub const NO_ERROR: u32 = 0x0;
+    pub const PROTOCOL_ERROR: u32 = 0x1;
+    pub const INTERNAL_ERROR: u32 = 0x2;
+    pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+    pub const STREAM_CLOSED: u32 = 0x5;
+    pub const FRAME_SIZE_ERROR: u32 = 0x6;
+    pub const REFUSED_STREAM: u32 = 0x7;
+    pub const COMPRESSION_ERROR: u32 = 0x9;
+    pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+}
+
+/// Headers that only mean something to a single HTTP/1 connection, they are not allowed in
+/// HTTP/2 requests and are left out of responses
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Why a connection had to be closed
+#[derive(Debug)]
+enum H2Error {
+    /// Reading or writing failed, nothing more can be sent
+    Io(io::Error),
+    /// The client broke the protocol, it is told so with a GOAWAY carrying `code`
+    Protocol { code: u32, reason: &'static str },
+}
+
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> Self {
+        H2Error::Io(e)
+    }
+}
+
+/// Shorthand for a connection error
+fn protocol_error<T>(code: u32, reason: &'static str) -> Result<T, H2Error> {
+    Err(H2Error::Protocol { code, reason })
+}
+
+/// A request that was sent over HTTP/1.1 asking to be upgraded to h2c, it is answered as
+/// stream 1 of the new connection
+pub(crate) struct Upgrade {
+    pub head: Vec<u8>,
+    pub body: Vec<u8>,
+    /// The decoded `HTTP2-Settings` header
+    pub settings: Vec<u8>,
+}
+
+/// Returns the decoded `HTTP2-Settings` of an HTTP/1.1 request head that asks to be upgraded to
+/// h2c, or `None` if it does not
+pub(crate) fn upgrade_settings(head: &[u8]) -> Option<Vec<u8>> {
+    let request_line = head.split(|c| *c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .find(|line| !line.is_empty())?;
+    if !request_line.ends_with(b" HTTP/1.1") {
+        return None;
+    }
+
+    let has_token = |value: &str, token: &str| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut settings = None;
+    for (key, value) in framing::header_lines(head) {
+        if key.eq_ignore_ascii_case("Upgrade") {
+            upgrade |= has_token(value, "h2c");
+        } else if key.eq_ignore_ascii_case("Connection") {
+            connection |= has_token(value, "Upgrade");
+        } else if key.eq_ignore_ascii_case("HTTP2-Settings") {
+            settings = base64url_decode(value);
+        }
+    }
+
+    match upgrade && connection {
+        true => settings,
+        false => None,
+    }
+}
+
+/// Decodes the unpadded base64url encoding used by `HTTP2-Settings`
+fn base64url_decode(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    for c in value.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | sextet as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Serves HTTP/2 over `stream` until the client or the server closes the connection
+///
+/// `buf` holds whatever has been read but not yet served, starting with the client's connection
+/// preface if it has arrived. Requests are answered one at a time, in order of their priority
+/// weight, while frames for other streams keep being received between the writes of a response.
+pub(crate) fn serve<T: Transport>(
+    stream: &mut T,
+    buf: Vec<u8>,
+    upgrade: Option<Upgrade>,
+    guard: &ConnectionGuard,
+    immortal: &Immortal,
+) {
+    let mut decoder = Decoder::new();
+    decoder.set_max_list_size(immortal.limits.max_header_size);
+
+    let mut connection = Connection {
+        peer_addr: stream.peer_addr(),
+        stream,
+        buf,
+        immortal,
+        guard,
+        decoder,
+        streams: BTreeMap::new(),
+        pending_headers: None,
+        last_stream_id: 0,
+        send_window: INITIAL_WINDOW,
+        initial_window: INITIAL_WINDOW,
+        max_frame_size: MAX_FRAME_SIZE,
+        served: 0,
+        going_away: false,
+        goaway_sent: false,
+    };
+
+    let code = match connection.run(upgrade) {
+        Ok(()) => code::NO_ERROR,
+        Err(H2Error::Protocol { code, reason: _reason }) => {
+            debug_eprintln!("ERROR: HTTP/2 connection error: {_reason}");
+            code
+        },
+        Err(H2Error::Io(_e)) => {
+            debug_eprintln!("{_e}");
+            return;
+        },
+    };
+    let _ = connection.write_goaway(code);
+}
+
+/// A frame as it was received
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// A header block that is still being received in CONTINUATION frames
+struct PendingHeaders {
+    stream_id: u32,
+    end_stream: bool,
+    weight: u16,
+    block: Vec<u8>,
+}
+
+/// A request stream and the state of its response
+struct Stream {
+    /// The request line and headers, translated to HTTP/1.1 for the request parser
+    head: Vec<u8>,
+    /// The head carries a `Content-Length` header
+    declares_length: bool,
+    body: Vec<u8>,
+    /// The client has sent the whole request
+    remote_closed: bool,
+    /// The request is waiting to be answered
+    ready: bool,
+    /// The status to answer with instead of dispatching the request, when it broke a limit
+    reject: Option<&'static str>,
+    send_window: i64,
+    weight: u16,
+}
+
+struct Connection<'a, T: Transport> {
+    stream: &'a mut T,
+    /// Bytes that have been read but not yet parsed into frames
+    buf: Vec<u8>,
+    immortal: &'a Immortal,
+    guard: &'a ConnectionGuard,
+    peer_addr: Option<PeerAddr>,
+    decoder: Decoder,
+    streams: BTreeMap<u32, Stream>,
+    pending_headers: Option<PendingHeaders>,
+    /// The highest stream id the client has opened
+    last_stream_id: u32,
+    /// How much DATA the client allows on the connection as a whole
+    send_window: i64,
+    /// The window streams start with, as set by the client
+    initial_window: i64,
+    /// The largest frame the client accepts
+    max_frame_size: usize,
+    served: usize,
+    /// No new streams are accepted
+    going_away: bool,
+    goaway_sent: bool,
+}
+
+impl<T: Transport> Connection<'_, T> {
+    fn run(&mut self, upgrade: Option<Upgrade>) -> Result<(), H2Error> {
+        if let Some(upgrade) = upgrade {
+            if !upgrade.settings.len().is_multiple_of(6) {
+                return protocol_error(code::PROTOCOL_ERROR, "malformed HTTP2-Settings");
+            }
+            self.apply_settings(&upgrade.settings)?;
+            self.stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")?;
+
+            // the upgraded request is answered on stream 1, which the client can no longer send on
+            self.last_stream_id = 1;
+            self.streams.insert(1, Stream {
+                head: upgrade.head,
+                declares_length: true,
+                body: upgrade.body,
+                remote_closed: true,
+                ready: true,
+                reject: None,
+                send_window: self.initial_window,
+                weight: 16,
+            });
+        }
+        self.write_settings()?;
+
+        let mut deadline = None;
+        if !self.fill(PREFACE.len(), self.immortal.header_read_timeout, &mut deadline)? {
+            return Ok(());
+        }
+        if !self.buf.starts_with(PREFACE) {
+            return protocol_error(code::PROTOCOL_ERROR, "invalid connection preface");
+        }
+        self.buf.drain(..PREFACE.len());
+
+        loop {
+            let max_requests = self.immortal.max_requests_per_connection;
+            if self.immortal.shutdown.is_shutdown() || (max_requests != 0 && self.served >= max_requests) {
+                self.going_away = true;
+            }
+            if self.going_away && !self.goaway_sent {
+                self.write_goaway(code::NO_ERROR)?;
+            }
+
+            while let Some(stream_id) = self.next_ready() {
+                self.respond(stream_id)?;
+            }
+            if self.going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            let idle = self.streams.is_empty() && self.pending_headers.is_none();
+            let timeout = match idle {
+                true => self.immortal.keep_alive_timeout,
+                false => self.immortal.body_read_timeout,
+            };
+            self.guard.set_idle(idle);
+            let frame = self.read_frame(timeout);
+            self.guard.set_idle(false);
+
+            match frame? {
+                Some(frame) => self.handle_frame(frame)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Reads until `buf` holds at least `len` bytes
+    ///
+    /// The client has `idle_timeout` to start sending, after which the bytes have to arrive
+    /// within the header read timeout. Returns false if the connection was closed or timed out.
+    fn fill(&mut self, len: usize, idle_timeout: Duration, deadline: &mut Option<Instant>) -> Result<bool, H2Error> {
+        let mut chunk = vec![0u8; self.immortal.read_buffer_size];
+        while self.buf.len() < len {
+            let timeout = if self.buf.is_empty() {
+                idle_timeout
+            } else {
+                let now = Instant::now();
+                deadline.get_or_insert(now + self.immortal.header_read_timeout)
+                    .saturating_duration_since(now)
+            };
+            if timeout.is_zero() {
+                return Ok(false);
+            }
+            self.stream.set_read_timeout(Some(timeout))?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(sz) => self.buf.extend_from_slice(&chunk[..sz]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads the next frame, returning `None` if the connection was closed or timed out
+    fn read_frame(&mut self, idle_timeout: Duration) -> Result<Option<Frame>, H2Error> {
+        let mut deadline = None;
+        if !self.fill(FRAME_HEADER_LEN, idle_timeout, &mut deadline)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return protocol_error(code::FRAME_SIZE_ERROR, "frame too large");
+        }
+        if !self.fill(FRAME_HEADER_LEN + len, idle_timeout, &mut deadline)? {
+            return Ok(None);
+        }
+
+        let frame = Frame {
+            kind: self.buf[3],
+            flags: self.buf[4],
+            stream_id: u32::from_be_bytes([self.buf[5], self.buf[6], self.buf[7], self.buf[8]]) & 0x7fff_ffff,
+            payload: self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec(),
+        };
+        self.buf.drain(..FRAME_HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), H2Error> {
+        // a header block must be finished before anything else is sent
+        if let Some(pending) = &self.pending_headers {
+            if frame.kind != kind::CONTINUATION || frame.stream_id != pending.stream_id {
+                return protocol_error(code::PROTOCOL_ERROR, "expected CONTINUATION");
+            }
+        }
+
+        match frame.kind {
+            kind::DATA => self.on_data(frame),
+            kind::HEADERS => self.on_headers(frame),
+            kind::PRIORITY => self.on_priority(frame),
+            kind::RST_STREAM => self.on_rst_stream(frame),
+            kind::SETTINGS => self.on_settings(frame),
+            kind::PUSH_PROMISE => protocol_error(code::PROTOCOL_ERROR, "PUSH_PROMISE from a client"),
+            kind::PING => self.on_ping(frame),
+            kind::GOAWAY => self.on_goaway(frame),
+            kind::WINDOW_UPDATE => self.on_window_update(frame),
+            kind::CONTINUATION => self.on_continuation(frame),
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return protocol_error(code::PROTOCOL_ERROR, "DATA on stream 0");
+        }
+        // the whole frame counts against flow control, the window is handed back straight away
+        // since bodies are buffered up to their size limit
+        let len = frame.payload.len();
+        if len > 0 {
+            self.write_window_update(0, len)?;
+        }
+        let data = strip_padding(&frame)?;
+        let end_stream = frame.flags & flag::END_STREAM != 0;
+        let max_body_size = self.immortal.limits.max_body_size;
+
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None if id > self.last_stream_id => return protocol_error(code::PROTOCOL_ERROR, "DATA on an idle stream"),
+            // the stream was reset or answered early, its remaining frames are discarded
+            None => return Ok(()),
+        };
+        if stream.remote_closed {
+            self.streams.remove(&id);
+            return self.write_rst_stream(id, code::STREAM_CLOSED);
+        }
+
+        if stream.reject.is_none() {
+            if stream.body.len() + data.len() > max_body_size {
+                // answered right away, the client is told to stop sending once it is
+                stream.reject = Some("413");
+                stream.body.clear();
+                stream.ready = true;
+            } else {
+                stream.body.extend_from_slice(data);
+            }
+        }
+
+        if end_stream {
+            stream.remote_closed = true;
+            stream.ready = true;
+        } else if len > 0 {
+            self.write_window_update(id, len)?;
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 || id.is_multiple_of(2) {
+            return protocol_error(code::PROTOCOL_ERROR, "HEADERS on an invalid stream");
+        }
+
+        let mut block = strip_padding(&frame)?;
+        let mut weight = 16;
+        if frame.flags & flag::PRIORITY != 0 {
+            if block.len() < 5 {
+                return protocol_error(code::FRAME_SIZE_ERROR, "HEADERS priority truncated");
+            }
+            weight = block[4] as u16 + 1;
+            block = &block[5..];
+        }
+
+        self.pending_headers = Some(PendingHeaders {
+            stream_id: id,
+            end_stream: frame.flags & flag::END_STREAM != 0,
+            weight,
+            block: block.to_vec(),
+        });
+        self.check_header_block_size()?;
+
+        if frame.flags & flag::END_HEADERS != 0 {
+            self.finish_headers()?;
+        }
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), H2Error> {
+        match &mut self.pending_headers {
+            Some(pending) => pending.block.extend_from_slice(&frame.payload),
+            None => return protocol_error(code::PROTOCOL_ERROR, "unexpected CONTINUATION"),
+        }
+        self.check_header_block_size()?;
+
+        if frame.flags & flag::END_HEADERS != 0 {
+            self.finish_headers()?;
+        }
+        Ok(())
+    }
+
+    /// Refuses to buffer header blocks much larger than the largest allowed header list, they
+    /// cannot be skipped without losing track of the compression state
+    fn check_header_block_size(&self) -> Result<(), H2Error> {
+        let limit = self.immortal.limits.max_header_size.max(MAX_FRAME_SIZE);
+        match &self.pending_headers {
+            Some(pending) if pending.block.len() > limit => protocol_error(code::ENHANCE_YOUR_CALM, "header block too large"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes a complete header block, opening a new stream or finishing one with trailers
+    fn finish_headers(&mut self) -> Result<(), H2Error> {
+        let pending = match self.pending_headers.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let id = pending.stream_id;
+
+        // every block is decoded to keep the compression state in step with the client
+        let fields = match self.decoder.decode(&pending.block) {
+            Ok(fields) => Some(fields),
+            Err(HpackError::ListTooLarge) => None,
+            Err(_e) => {
+                debug_eprintln!("ERROR: HPACK: {_e}");
+                return protocol_error(code::COMPRESSION_ERROR, "header block could not be decoded");
+            },
+        };
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // trailers, which are not passed on to handlers
+            if stream.remote_closed || !pending.end_stream {
+                self.streams.remove(&id);
+                return self.write_rst_stream(id, code::PROTOCOL_ERROR);
+            }
+            stream.remote_closed = true;
+            stream.ready = stream.reject.is_none() || stream.ready;
+            return Ok(());
+        }
+
+        if id <= self.last_stream_id {
+            return protocol_error(code::STREAM_CLOSED, "HEADERS on a closed stream");
+        }
+        self.last_stream_id = id;
+
+        // the GOAWAY already told the client this stream will not be served
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return self.write_rst_stream(id, code::REFUSED_STREAM);
+        }
+
+        let (head, declares_length, reject) = match fields {
+            None => (Vec::new(), false, Some("431")),
+            Some(fields) => match http1_head(&fields) {
+                Ok((head, declares_length)) => (head, declares_length, None),
+                Err(_reason) => {
+                    debug_eprintln!("ERROR: Malformed HTTP/2 request: {_reason}");
+                    return self.write_rst_stream(id, code::PROTOCOL_ERROR);
+                },
+            },
+        };
+
+        self.streams.insert(id, Stream {
+            head,
+            declares_length,
+            body: Vec::new(),
+            remote_closed: pending.end_stream,
+            ready: pending.end_stream || reject.is_some(),
+            reject,
+            send_window: self.initial_window,
+            weight: pending.weight,
+        });
+        Ok(())
+    }
+
+    fn on_priority(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream_id == 0 {
+            return protocol_error(code::PROTOCOL_ERROR, "PRIORITY on stream 0");
+        }
+        if frame.payload.len() != 5 {
+            self.streams.remove(&frame.stream_id);
+            return self.write_rst_stream(frame.stream_id, code::FRAME_SIZE_ERROR);
+        }
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            stream.weight = frame.payload[4] as u16 + 1;
+        }
+        Ok(())
+    }
+
+    fn on_rst_stream(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return protocol_error(code::PROTOCOL_ERROR, "RST_STREAM on an idle stream");
+        }
+        if frame.payload.len() != 4 {
+            return protocol_error(code::FRAME_SIZE_ERROR, "RST_STREAM of the wrong size");
+        }
+        self.streams.remove(&frame.stream_id);
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream_id != 0 {
+            return protocol_error(code::PROTOCOL_ERROR, "SETTINGS on a stream");
+        }
+        if frame.flags & flag::ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => protocol_error(code::FRAME_SIZE_ERROR, "SETTINGS acknowledgement with a payload"),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return protocol_error(code::FRAME_SIZE_ERROR, "SETTINGS of the wrong size");
+        }
+        self.apply_settings(&frame.payload)?;
+        self.write_frame(kind::SETTINGS, flag::ACK, 0, &[])
+    }
+
+    /// Applies the client's settings, `payload` must be a whole number of settings
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        for entry in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([entry[0], entry[1]]);
+            let value = u32::from_be_bytes([entry[2], entry[3], entry[4], entry[5]]);
+            match id {
+                setting::ENABLE_PUSH if value > 1 => {
+                    return protocol_error(code::PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH");
+                },
+                setting::INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return protocol_error(code::FLOW_CONTROL_ERROR, "invalid SETTINGS_INITIAL_WINDOW_SIZE");
+                    }
+                    // open streams are adjusted by the difference
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return protocol_error(code::FLOW_CONTROL_ERROR, "stream window too large");
+                        }
+                    }
+                    self.initial_window = value;
+                },
+                setting::MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return protocol_error(code::PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE");
+                    }
+                    self.max_frame_size = value as usize;
+                },
+                // the header table size does not matter since the encoder never indexes, and
+                // the server never pushes
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn on_ping(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream_id != 0 {
+            return protocol_error(code::PROTOCOL_ERROR, "PING on a stream");
+        }
+        if frame.payload.len() != 8 {
+            return protocol_error(code::FRAME_SIZE_ERROR, "PING of the wrong size");
+        }
+        match frame.flags & flag::ACK {
+            0 => self.write_frame(kind::PING, flag::ACK, 0, &frame.payload),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_goaway(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.stream_id != 0 {
+            return protocol_error(code::PROTOCOL_ERROR, "GOAWAY on a stream");
+        }
+        // the streams already open are still answered
+        self.going_away = true;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if frame.payload.len() != 4 {
+            return protocol_error(code::FRAME_SIZE_ERROR, "WINDOW_UPDATE of the wrong size");
+        }
+        let id = frame.stream_id;
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as i64;
+
+        if id == 0 {
+            if increment == 0 {
+                return protocol_error(code::PROTOCOL_ERROR, "empty WINDOW_UPDATE");
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return protocol_error(code::FLOW_CONTROL_ERROR, "connection window too large");
+            }
+            return Ok(());
+        }
+
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None if id > self.last_stream_id => return protocol_error(code::PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"),
+            None => return Ok(()),
+        };
+        stream.send_window += increment;
+        if increment == 0 || stream.send_window > MAX_WINDOW {
+            self.streams.remove(&id);
+            let code = match increment {
+                0 => code::PROTOCOL_ERROR,
+                _ => code::FLOW_CONTROL_ERROR,
+            };
+            return self.write_rst_stream(id, code);
+        }
+        Ok(())
+    }
+
+    /// The complete request with the highest priority weight, oldest first among equals
+    fn next_ready(&self) -> Option<u32> {
+        self.streams.iter()
+            .filter(|(_, stream)| stream.ready)
+            .max_by_key(|(id, stream)| (stream.weight, Reverse(**id)))
+            .map(|(id, _)| *id)
+    }
+
+    /// Dispatches the request on a stream and writes its response
+    fn respond(&mut self, id: u32) -> Result<(), H2Error> {
+        let (mut head, declares_length, body, reject) = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                stream.ready = false;
+                (std::mem::take(&mut stream.head), stream.declares_length, std::mem::take(&mut stream.body), stream.reject)
+            },
+            None => return Ok(()),
+        };
+
+        if !declares_length && !body.is_empty() {
+            let at = head.len() - 2;
+            head.splice(at..at, format!("Content-Length: {}\r\n", body.len()).into_bytes());
+        }
+        let request = match reject {
+            Some(code) => Err(code),
+            None => Request::from_head_body(&head, (!body.is_empty()).then_some(body.as_slice()), self.peer_addr.as_ref())
+                .map_err(|_| "400"),
+        };
+        let (request_rc, response_rc) = match request {
+            Ok(mut request) => {
+                request.version = "2.0";
+                dispatch(request, self.immortal)
+            },
+            Err(code) => {
+                let mut response = Response::bad();
+                response.code = code;
+                (Rc::new(RefCell::new(Request::bad())), Rc::new(RefCell::new(response)))
+            },
+        };
+
+        let written = self.write_response(id, &mut response_rc.borrow_mut());
+        log(self.peer_addr.as_ref(), request_rc, response_rc, *written.as_ref().unwrap_or(&0), self.immortal);
+        written?;
+        self.served += 1;
+
+        // a client still sending a request that was answered early is told to stop
+        if let Some(stream) = self.streams.remove(&id) {
+            if !stream.remote_closed {
+                self.write_rst_stream(id, code::NO_ERROR)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a response as HEADERS and DATA frames, returning the amount of bytes written
+    fn write_response(&mut self, id: u32, response: &mut Response) -> Result<usize, H2Error> {
+        response.resolve_status();
+        response.insert_generated_headers();
+        let body_stream = response.stream.take();
+        let length = match &body_stream {
+            Some(stream) => stream.length,
+            None => Some(response.body.len()),
+        };
+        let head_only = response.method == "HEAD";
+
+        let mut fields: Vec<(Vec<u8>, Vec<u8>)> = vec![(b":status".to_vec(), response.code.as_bytes().to_vec())];
+        for (name, value) in response.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if name.is_empty() || name == "content-length" || CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            fields.push((name.into_bytes(), value.as_bytes().to_vec()));
+        }
+        if let (Some(length), false) = (length, head_only) {
+            fields.push((b"content-length".to_vec(), length.to_string().into_bytes()));
+        }
+        let block = hpack::encode(fields.iter().map(|(name, value)| (name.as_slice(), value.as_slice())));
+
+        let no_body = head_only || length == Some(0);
+        let mut sent = self.write_headers(id, &block, no_body)?;
+        if no_body {
+            return Ok(sent);
+        }
+
+        let body_stream = match body_stream {
+            None => {
+                let body = std::mem::take(&mut response.body);
+                return Ok(sent + self.write_data(id, &body, true)?.unwrap_or(0));
+            },
+            Some(body_stream) => body_stream,
+        };
+
+        let mut body_sent: usize = 0;
+        for chunk in body_stream.chunks {
+            let mut chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_e) => {
+                    debug_eprintln!("ERROR: Body stream failed: {_e}");
+                    self.streams.remove(&id);
+                    self.write_rst_stream(id, code::INTERNAL_ERROR)?;
+                    return Ok(sent);
+                },
+            };
+            if let Some(length) = length {
+                chunk.truncate(length - body_sent);
+            }
+            if chunk.is_empty() {
+                continue;
+            }
+            body_sent += chunk.len();
+            match self.write_data(id, &chunk, false)? {
+                Some(written) => sent += written,
+                None => return Ok(sent),
+            }
+        }
+
+        if length.is_some_and(|length| length != body_sent) {
+            debug_eprintln!("ERROR: Body stream ended after {body_sent} of {length:?} bytes");
+            self.streams.remove(&id);
+            self.write_rst_stream(id, code::INTERNAL_ERROR)?;
+            return Ok(sent);
+        }
+        Ok(sent + self.write_data(id, &[], true)?.unwrap_or(0))
+    }
+
+    /// Writes a header block, split over CONTINUATION frames if it does not fit in one frame
+    fn write_headers(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<usize, H2Error> {
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = kind::HEADERS;
+        let mut flags = match end_stream {
+            true => flag::END_STREAM,
+            false => 0,
+        };
+        let mut sent = 0;
+        // an empty block still needs its HEADERS frame
+        let first: &[u8] = fragments.next().unwrap_or_default();
+        let mut fragment = Some(first);
+        while let Some(data) = fragment {
+            if fragments.peek().is_none() {
+                flags |= flag::END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, data)?;
+            sent += FRAME_HEADER_LEN + data.len();
+            kind = kind::CONTINUATION;
+            flags = 0;
+            fragment = fragments.next();
+        }
+        Ok(sent)
+    }
+
+    /// Writes DATA frames as the flow control windows allow, reading frames from the client while
+    /// waiting for them to open
+    ///
+    /// Returns `None` if the stream was reset by the client before all of the data was written.
+    fn write_data(&mut self, id: u32, mut data: &[u8], end_stream: bool) -> Result<Option<usize>, H2Error> {
+        let mut sent = 0;
+        loop {
+            let stream_window = match self.streams.get(&id) {
+                Some(stream) => stream.send_window,
+                None => return Ok(None),
+            };
+            let window = self.send_window.min(stream_window).min(self.max_frame_size as i64);
+            if window <= 0 && !data.is_empty() {
+                let frame = match self.read_frame(self.immortal.write_timeout)? {
+                    Some(frame) => frame,
+                    None => return Err(H2Error::Io(io::ErrorKind::TimedOut.into())),
+                };
+                self.handle_frame(frame)?;
+                continue;
+            }
+
+            let (chunk, rest) = data.split_at(data.len().min(window.max(0) as usize));
+            let flags = match rest.is_empty() && end_stream {
+                true => flag::END_STREAM,
+                false => 0,
+            };
+            self.write_frame(kind::DATA, flags, id, chunk)?;
+            sent += FRAME_HEADER_LEN + chunk.len();
+            self.send_window -= chunk.len() as i64;
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.send_window -= chunk.len() as i64;
+            }
+
+            data = rest;
+            if data.is_empty() {
+                return Ok(Some(sent));
+            }
+        }
+    }
+
+    fn write_settings(&mut self) -> Result<(), H2Error> {
+        let mut payload = Vec::with_capacity(12);
+        payload.extend_from_slice(&setting::MAX_CONCURRENT_STREAMS.to_be_bytes());
+        payload.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        payload.extend_from_slice(&setting::MAX_HEADER_LIST_SIZE.to_be_bytes());
+        payload.extend_from_slice(&(self.immortal.limits.max_header_size as u32).to_be_bytes());
+        self.write_frame(kind::SETTINGS, 0, 0, &payload)
+    }
+
+    fn write_window_update(&mut self, id: u32, increment: usize) -> Result<(), H2Error> {
+        self.write_frame(kind::WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes())
+    }
+
+    fn write_rst_stream(&mut self, id: u32, code: u32) -> Result<(), H2Error> {
+        self.write_frame(kind::RST_STREAM, 0, id, &code.to_be_bytes())
+    }
+
+    /// Tells the client the last stream that will be answered and stops accepting new ones
+    fn write_goaway(&mut self, code: u32) -> Result<(), H2Error> {
+        if self.goaway_sent {
+            return Ok(());
+        }
+        self.going_away = true;
+        self.goaway_sent = true;
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&self.last_stream_id.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(kind::GOAWAY, 0, 0, &payload)
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), H2Error> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the payload of a DATA or HEADERS frame without its padding
+fn strip_padding(frame: &Frame) -> Result<&[u8], H2Error> {
+    if frame.flags & flag::PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let pad_len = match frame.payload.first() {
+        Some(pad_len) => *pad_len as usize,
+        None => return protocol_error(code::FRAME_SIZE_ERROR, "padded frame without a pad length"),
+    };
+    if pad_len >= frame.payload.len() {
+        return protocol_error(code::PROTOCOL_ERROR, "padding longer than the frame");
+    }
+    Ok(&frame.payload[1..frame.payload.len() - pad_len])
+}
+
+/// Translates the header fields of an HTTP/2 request into an HTTP/1.1 request head, so that it
+/// can go through the same parser as every other request
+///
+/// Also returns whether the request declared a `Content-Length`.
+fn http1_head(fields: &[Field]) -> Result<(Vec<u8>, bool), &'static str> {
+    let mut method = None;
+    let mut path = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut headers: Vec<(&str, &str)> = Vec::new();
+    let mut cookies: Vec<&str> = Vec::new();
+    let mut has_host = false;
+    let mut declares_length = false;
+
+    for (name, value) in fields {
+        let name = std::str::from_utf8(name).map_err(|_| "header name is not UTF-8")?;
+        let value = std::str::from_utf8(value).map_err(|_| "header value is not UTF-8")?;
+        // anything that could end a line would let the client smuggle in extra headers
+        if value.contains(['\r', '\n', '\0']) || name.contains(['\r', '\n', '\0', ' ']) || name.is_empty() {
+            return Err("header contains invalid characters");
+        }
+
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err("pseudo-header after a regular header");
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err("unknown pseudo-header"),
+            };
+            if slot.replace(value).is_some() {
+                return Err("repeated pseudo-header");
+            }
+            continue;
+        }
+
+        if name.contains(':') || name.bytes().any(|c| c.is_ascii_uppercase()) {
+            return Err("header name is invalid");
+        }
+        if CONNECTION_HEADERS.contains(&name) || (name == "te" && value != "trailers") {
+            return Err("connection-specific header");
+        }
+        match name {
+            "cookie" => cookies.push(value),
+            _ => {
+                has_host |= name == "host";
+                declares_length |= name == "content-length";
+                headers.push((name, value));
+            },
+        }
+    }
+
+    let (method, path) = match (method, path, scheme) {
+        (Some(method), Some(path), Some(_)) if !path.is_empty() && !method.contains(' ') && !path.contains(' ') => (method, path),
+        _ => return Err("missing or invalid pseudo-headers"),
+    };
+
+    let mut head = format!("{method} {path} HTTP/1.1\r\n");
+    if let (Some(authority), false) = (authority, has_host) {
+        head.push_str(&format!("Host: {authority}\r\n"));
+    }
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !cookies.is_empty() {
+        head.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+    head.push_str("\r\n");
+    Ok((head.into_bytes(), declares_length))
+}
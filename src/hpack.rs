@@ -0,0 +1,362 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::error;
+
+use lazy_static::lazy_static;
+
+/// The size of the dynamic table a decoder starts with, and the most a client may grow it to
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Every entry in the dynamic table costs this much on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HpackError {
+    /// The header block ended in the middle of a field
+    Truncated,
+    /// An integer does not fit in a `usize`
+    IntegerOverflow,
+    /// A field refers to an entry that is in neither table
+    IndexInvalid(usize),
+    /// A Huffman coded string is malformed
+    HuffmanInvalid,
+    /// A table size update exceeds the allowed maximum or comes after the first field
+    TableSizeInvalid(usize),
+    /// The decoded header list is larger than allowed, the table was still kept up to date
+    ListTooLarge,
+}
+impl Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl error::Error for HpackError {}
+
+/// A decoded header field, as its name and value
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Decodes HTTP/2 header blocks as described in RFC 7541, it keeps the dynamic table between
+/// blocks so every block of a connection must go through the same decoder.
+#[derive(Debug)]
+pub struct Decoder {
+    table: VecDeque<Field>,
+    table_size: usize,
+    max_table_size: usize,
+    max_list_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    /// Creates a decoder with an empty dynamic table and no limit on the header list size
+    pub fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            table_size: 0,
+            max_table_size: DEFAULT_TABLE_SIZE,
+            max_list_size: usize::MAX,
+        }
+    }
+
+    /// Sets the largest header list `decode` returns, counted the same way as table entries
+    pub fn set_max_list_size(&mut self, size: usize) {
+        self.max_list_size = size;
+    }
+
+    /// Decodes a complete header block into its fields
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Field>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list_size: usize = 0;
+        let mut pos = 0;
+
+        while pos < block.len() {
+            let first = block[pos];
+            let field = if first & 0x80 != 0 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                self.entry(index)?
+            } else if first & 0x40 != 0 {
+                let field = self.decode_literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > DEFAULT_TABLE_SIZE || !fields.is_empty() {
+                    return Err(HpackError::TableSizeInvalid(size));
+                }
+                self.max_table_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                self.decode_literal(block, &mut pos, 4)?
+            };
+
+            // keep decoding so the table stays in step with the client
+            list_size = list_size.saturating_add(field.0.len() + field.1.len() + ENTRY_OVERHEAD);
+            if list_size <= self.max_list_size {
+                fields.push(field);
+            }
+        }
+
+        if list_size > self.max_list_size {
+            return Err(HpackError::ListTooLarge);
+        }
+        Ok(fields)
+    }
+
+    /// Decodes a literal field whose name index has a prefix of `prefix` bits
+    fn decode_literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<Field, HpackError> {
+        let name = match decode_integer(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.entry(index)?.0,
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+
+    /// Looks up an entry in the static table, followed by the dynamic table
+    fn entry(&self, index: usize) -> Result<Field, HpackError> {
+        if index == 0 {
+            return Err(HpackError::IndexInvalid(index));
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.table.get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or(HpackError::IndexInvalid(index))
+    }
+
+    /// Adds a field to the front of the dynamic table, evicting the oldest entries to fit it
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry larger than the whole table empties it and is not added
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Evicts entries until `room` more bytes fit in the dynamic table
+    fn evict(&mut self, room: usize) {
+        while self.table_size + room > self.max_table_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes header fields into a header block for HTTP/2, `fields` must have lowercase names
+///
+/// Nothing is added to the dynamic table and Huffman coding is not used, which keeps the block
+/// valid whatever table size the client asked for.
+pub fn encode<'a, I>(fields: I) -> Vec<u8> where I: IntoIterator<Item = (&'a [u8], &'a [u8])> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        // literal without indexing, reusing the name from the static table where possible
+        match STATIC_TABLE.iter().position(|(static_name, _)| static_name.as_bytes() == name) {
+            Some(index) => encode_integer(&mut block, index + 1, 4, 0x00),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            },
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// Decodes an integer with a prefix of `prefix` bits starting at `pos`
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask = ((1u16 << prefix) - 1) as u8;
+    let first = block.get(*pos).ok_or(HpackError::Truncated)? & mask;
+    *pos += 1;
+    if first < mask {
+        return Ok(first as usize);
+    }
+
+    let mut value = mask as usize;
+    let mut shift: u32 = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or(HpackError::Truncated)?;
+        *pos += 1;
+        let part = ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .filter(|part| part >> shift == (byte & 0x7f) as usize)
+            .ok_or(HpackError::IntegerOverflow)?;
+        value = value.checked_add(part).ok_or(HpackError::IntegerOverflow)?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Decodes a length prefixed string, which may be Huffman coded
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<Vec<u8>, HpackError> {
+    let huffman = block.get(*pos).ok_or(HpackError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, pos, 7)?;
+    let end = pos.checked_add(len)
+        .filter(|end| *end <= block.len())
+        .ok_or(HpackError::Truncated)?;
+    let data = &block[*pos..end];
+    *pos = end;
+    match huffman {
+        true => huffman_decode(data),
+        false => Ok(data.to_vec()),
+    }
+}
+
+/// Encodes an integer with a prefix of `prefix` bits, `flags` fills the bits above the prefix
+fn encode_integer(block: &mut Vec<u8>, mut value: usize, prefix: u8, flags: u8) {
+    let mask = ((1u16 << prefix) - 1) as usize;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Encodes a string as a plain length prefixed literal
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(block, data.len(), 7, 0x00);
+    block.extend_from_slice(data);
+}
+
+/// Decodes a Huffman coded string
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut code: u32 = 0;
+    let mut len: u8 = 0;
+    for byte in data {
+        for bit in (0..8).rev() {
+            code = (code << 1) | ((byte >> bit) & 1) as u32;
+            len += 1;
+            match HUFFMAN_DECODE.get(&(code, len)) {
+                // the end of string symbol must not appear in the data
+                Some(256) => return Err(HpackError::HuffmanInvalid),
+                Some(symbol) => {
+                    decoded.push(*symbol as u8);
+                    code = 0;
+                    len = 0;
+                },
+                None if len >= 30 => return Err(HpackError::HuffmanInvalid),
+                None => {},
+            }
+        }
+    }
+    // the string is padded with at most 7 bits of the end of string symbol, which are all ones
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(HpackError::HuffmanInvalid);
+    }
+    Ok(decoded)
+}
+
+lazy_static! {
+    static ref HUFFMAN_DECODE: HashMap<(u32, u8), u16> = HUFFMAN_CODES.iter()
+        .enumerate()
+        .map(|(symbol, (code, len))| ((*code, *len), symbol as u16))
+        .collect();
+}
+
+/// The static table from RFC 7541 Appendix A, index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"),
+    (":path", "/index.html"), (":scheme", "http"), (":scheme", "https"), (":status", "200"),
+    (":status", "204"), (":status", "206"), (":status", "304"), (":status", "400"),
+    (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""),
+    ("accept", ""), ("access-control-allow-origin", ""), ("age", ""), ("allow", ""),
+    ("authorization", ""), ("cache-control", ""), ("content-disposition", ""),
+    ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""),
+    ("date", ""), ("etag", ""), ("expect", ""), ("expires", ""), ("from", ""), ("host", ""),
+    ("if-match", ""), ("if-modified-since", ""), ("if-none-match", ""), ("if-range", ""),
+    ("if-unmodified-since", ""), ("last-modified", ""), ("link", ""), ("location", ""),
+    ("max-forwards", ""), ("proxy-authenticate", ""), ("proxy-authorization", ""),
+    ("range", ""), ("referer", ""), ("refresh", ""), ("retry-after", ""), ("server", ""),
+    ("set-cookie", ""), ("strict-transport-security", ""), ("transfer-encoding", ""),
+    ("user-agent", ""), ("vary", ""), ("via", ""), ("www-authenticate", ""),
+];
+
+/// The Huffman code from RFC 7541 Appendix B as (code, length in bits), indexed by symbol
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
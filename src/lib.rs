@@ -3,35 +3,70 @@
 use std::cell::RefCell;
 use std::fmt::Display;
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::net::{TcpListener, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::error;
 use std::thread::{self, JoinHandle};
 use std::sync::Arc;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
 
+pub mod config;
 pub mod context;
 pub mod cookie;
+#[cfg(all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c")))]
+mod event_loop;
+pub mod framing;
+#[cfg(feature = "h2c")]
+pub mod h2;
+#[cfg(feature = "h2c")]
+pub mod hpack;
+pub mod logger;
+pub mod metrics;
 pub mod middleware;
+pub mod recovery;
 pub mod request;
 pub mod response;
 pub mod router;
+#[cfg(feature = "tokio")]
+pub mod runtime;
 pub mod session;
+pub mod state;
+pub mod shutdown;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
 pub mod util;
 
 pub use request::Request;
 use request::RequestError;
 pub use response::Response;
 pub use context::Context;
+pub use config::ServerConfig;
+use framing::{Frame, Framer, FramingError, Limits};
+use metrics::Metrics;
 use middleware::Middleware;
-use router::{Router, Handler};
+use recovery::{HandlerPanic, PanicHook};
+use logger::{AccessLogger, AccessRecord, LogFormat, Logger};
+use router::{Router, RouteError};
 use session::SessionManager;
-use util::{strip_for_terminal, code_color};
+use state::State;
+use shutdown::{Connections, ConnectionGuard};
+pub use shutdown::ShutdownHandle;
+use transport::{Listener, PeerAddr};
+pub use transport::Transport;
 use uuid::Uuid;
 
 use chrono::Utc;
-use colored::*;
 use debug_print::debug_eprintln;
 
 #[derive(Debug)]
@@ -44,6 +79,9 @@ pub enum ImmortalError<'a> {
     /// TCP accept() failed
     AcceptError(io::Error),
     RequestError(RequestError<'a>),
+    /// TLS configuration failed
+    #[cfg(feature = "tls")]
+    Tls(tls::TlsError),
 }
 
 impl Display for ImmortalError<'_> {
@@ -53,146 +91,613 @@ impl Display for ImmortalError<'_> {
 }
 impl error::Error for ImmortalError<'_> {}
 
+/// Passes a record of the response to the logger, if there is one
 #[inline]
-fn log(stream: &TcpStream, req: Rc<RefCell<Request>>, resp: Rc<RefCell<Response>>, sent: usize) {
-    let remote_socket = match stream.peer_addr() {
-        Err(_) => "<no socket>".red().bold(),
-        Ok(s) => s.ip().to_string().normal(),
+fn log(peer_addr: Option<&PeerAddr>, req: Rc<RefCell<Request>>, resp: Rc<RefCell<Response>>, sent: usize, immortal: &Immortal) {
+    let logger = match &immortal.logger {
+        None => return,
+        Some(logger) => logger,
     };
 
-    let now = Utc::now();
-    let date_time = now.format("%a, %d %b %Y %H:%M:%S").to_string();
-    let time_stamp = format!("[{:<17}]",
-                             format!("{}.{}",
-                                now.timestamp(),
-                                now.timestamp_subsec_micros())
-                            .bright_blue());
-
-    let method = match req.borrow().method {
-        "" => "<no method>".red().bold(),
-        _ => strip_for_terminal(req.borrow().method).normal(),
+    let mut request = req.borrow_mut();
+    let record = AccessRecord {
+        time: Utc::now(),
+        duration: request.received.map(|received| received.elapsed()).unwrap_or_default(),
+        peer_addr,
+        method: request.method,
+        document: request.document,
+        query: request.query_raw,
+        version: request.version,
+        code: resp.borrow().code,
+        sent,
+        user_agent: request.header("User-Agent"),
+        referer: request.header("Referer"),
+        session_id: Some(request.session_id).filter(|id| !id.is_nil()),
     };
+    logger.log(&record);
+}
+
+/// Writes the response to the stream and logs it
+///
+/// Returns false if the response could not be written completely.
+#[inline]
+fn stream_write<T: Transport>(
+    stream: &mut T,
+    request: Rc<RefCell<Request>>,
+    response: Rc<RefCell<Response>>,
+    immortal: &Immortal,
+) -> bool {
+    let peer_addr = stream.peer_addr();
+    let written = response.borrow_mut().write_to(stream);
+    match written {
+        Ok(sent) => {
+            log(peer_addr.as_ref(), request, response, sent, immortal);
+            true
+        },
+        Err(_e) => {
+            debug_eprintln!("{}", _e);
+            log(peer_addr.as_ref(), request, response, 0, immortal);
+            false
+        },
+    }
+}
+
+/// Returns true if the client asked for the connection to be kept open after this request.
+///
+/// HTTP/1.1 connections are persistent unless the client sends `Connection: close`, older
+/// versions must opt in with `Connection: keep-alive`.
+fn wants_keep_alive(request: &mut Request) -> bool {
+    let persistent_by_default = request.version == "1.1";
+    match request.header("Connection") {
+        None => persistent_by_default,
+        Some(value) => {
+            let mut tokens = value.split(',').map(|token| token.trim());
+            if tokens.clone().any(|token| token.eq_ignore_ascii_case("close")) {
+                false
+            } else if tokens.any(|token| token.eq_ignore_ascii_case("keep-alive")) {
+                true
+            } else {
+                persistent_by_default
+            }
+        },
+    }
+}
+
+/// Reasons a connection stopped before a complete request could be read from it
+enum ReadError {
+    /// The request was malformed or exceeded the size limits
+    Framing(FramingError),
+    /// The client took too long to send the request head or body
+    TimedOut,
+}
+
+impl ReadError {
+    /// The status code the client should be answered with
+    fn status_code(&self) -> &'static str {
+        match self {
+            ReadError::Framing(e) => e.status_code(),
+            ReadError::TimedOut => "408",
+        }
+    }
+}
 
-    let document = match req.borrow().document {
-        "" => "<no document>".red().bold(),
-        _ => strip_for_terminal(req.borrow().document).normal(),
+impl From<FramingError> for ReadError {
+    fn from(e: FramingError) -> Self {
+        ReadError::Framing(e)
+    }
+}
+
+/// Reads from the stream until a complete request is buffered in `buf`
+///
+/// The client may take up to `idle_timeout` to start a request, after which the head and body
+/// each have to arrive within their own timeouts, however slowly the bytes trickle in.
+///
+/// Returns `Ok(None)` if the connection was closed, failed or idled out before a request was
+/// started, or if the request was already given a final response before its body was read.
+fn read_frame<T: Transport>(
+    stream: &mut T,
+    buf: &mut Vec<u8>,
+    idle_timeout: Duration,
+    peer_addr: Option<&PeerAddr>,
+    server_name: Option<&str>,
+    immortal: &Immortal,
+) -> Result<Option<Frame>, ReadError> {
+    let mut chunk = vec![0u8; immortal.read_buffer_size];
+    let mut framer = Framer::default();
+    let mut head_deadline: Option<Instant> = None;
+    let mut body_deadline: Option<Instant> = None;
+    loop {
+        if let Some(frame) = framer.frame(buf, &immortal.limits)? {
+            return Ok(Some(frame));
+        }
+
+        // the body deadline is only unset the first time round with a complete head
+        if let (Some(head_len), None) = (framer.head_len(), body_deadline) {
+            if !answer_expectation(stream, &buf[..head_len], peer_addr, server_name, immortal) {
+                return Ok(None);
+            }
+        }
+
+        let timeout = read_timeout(buf, &framer, idle_timeout, &mut head_deadline, &mut body_deadline, immortal);
+        if timeout.is_zero() || stream.set_read_timeout(Some(timeout)).is_err() {
+            return if buf.is_empty() { Ok(None) } else { Err(ReadError::TimedOut) };
+        }
+
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(None),
+            Ok(sz) => buf.extend_from_slice(&chunk[..sz]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if !buf.is_empty() && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(ReadError::TimedOut);
+            },
+            Err(_e) => {
+                debug_eprintln!("{}", _e);
+                return Ok(None);
+            },
+        }
+    }
+}
+
+/// How long the next read may block for, starting the head and body deadlines as the request
+/// comes in
+fn read_timeout(
+    buf: &[u8],
+    framer: &Framer,
+    idle_timeout: Duration,
+    head_deadline: &mut Option<Instant>,
+    body_deadline: &mut Option<Instant>,
+    immortal: &Immortal,
+) -> Duration {
+    if buf.is_empty() {
+        return idle_timeout;
+    }
+    let now = Instant::now();
+    let deadline = if framer.head_len().is_some() {
+        *body_deadline.get_or_insert(now + immortal.body_read_timeout)
+    } else {
+        *head_deadline.get_or_insert(now + immortal.header_read_timeout)
     };
+    deadline.saturating_duration_since(now)
+}
 
-    let user_agent = match req.borrow_mut().header("User-Agent") {
-        None => "<no user-agent>".red().bold(),
-        Some(thing) => strip_for_terminal(thing).normal(),
+/// Answers a request that sent `Expect: 100-continue` once its head has arrived, the client waits
+/// for `100 Continue` before sending the body
+///
+/// Header middleware inspects the request first, if it rejects the request by setting a status
+/// other than 200 that is sent as the final response instead. Returns false if a final response
+/// was sent, in which case the body is never read.
+fn answer_expectation<T: Transport>(
+    stream: &mut T,
+    head: &[u8],
+    peer_addr: Option<&PeerAddr>,
+    server_name: Option<&str>,
+    immortal: &Immortal,
+) -> bool {
+    let expectation = match framing::header_lines(head).find(|(key, _)| key.eq_ignore_ascii_case("Expect")) {
+        None => return true,
+        Some((_, value)) => value,
+    };
+    // malformed requests are answered once they have been read, and HTTP/1.0 clients do not
+    // understand interim responses
+    let mut request = match Request::from_head_body(head, None, peer_addr) {
+        Ok(request) if request.version == "1.1" => request,
+        _ => return true,
     };
+    request.server_name = server_name;
 
-    println!("{}  {}  {}  {}\t{}  {}\t{}\t{}",
-             date_time,
-             time_stamp,
-             remote_socket,
-             method,
-             code_color(resp.borrow().code),
-             sent,
-             if req.borrow_mut().query_raw.is_empty() {
-                document
-             } else {
-                format!("{}?{}", document, &strip_for_terminal(req.borrow_mut().query_raw)).normal()
-             },
-             user_agent);
+    let (request_rc, response_rc) = match expectation.eq_ignore_ascii_case("100-continue") {
+        true => {
+            let (request_rc, response_rc, _) = run_handlers(request, immortal, |ctx| immortal.header_middleware.run(ctx));
+            (request_rc, response_rc)
+        },
+        false => {
+            let mut response = Response::bad();
+            response.code = "417";
+            (Rc::new(RefCell::new(request)), Rc::new(RefCell::new(response)))
+        },
+    };
+    if response_rc.borrow().code == "200" {
+        return stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").is_ok();
+    }
+
+    // the client may already be sending the body, so the connection cannot be reused
+    response_rc.borrow_mut().headers.insert("Connection", "close".to_string());
+    stream_write(stream, request_rc, response_rc, immortal);
+    false
 }
 
-#[inline]
-fn stream_write(stream: &mut TcpStream, request: Rc<RefCell<Request>>, response: Rc<RefCell<Response>>) {
-    let data = response.borrow_mut().serialize();
-    match stream.write(data.as_slice()) {
-        Ok(sent) => {
-            log(stream, request, response, sent);
+/// Parses the request described by `frame` out of the start of `buf`
+fn frame_request<'buf>(
+    buf: &'buf [u8],
+    frame: &'buf Frame,
+    peer_addr: Option<&PeerAddr>
+) -> Result<Request<'buf>, RequestError<'buf>> {
+    match &frame.decoded_body {
+        Some(body) => Request::from_head_body(&buf[..frame.head_len], Some(body), peer_addr),
+        None => Request::new(&buf[..frame.len()], peer_addr),
+    }
+}
+
+/// Parses a single framed request, runs it through the middleware and router and writes the
+/// response back to the client.
+///
+/// Returns true if the connection should be kept open for another request.
+fn serve_request<T: Transport>(
+    stream: &mut T,
+    buf: &[u8],
+    frame: &Frame,
+    peer_addr: Option<&PeerAddr>,
+    server_name: Option<&str>,
+    served: usize,
+    immortal: &Immortal,
+) -> bool {
+    let mut request = match frame_request(buf, frame, peer_addr) {
+        Err(RequestError::ProtoVersionInvalid(_)) => {
+            write_status(stream, "505", immortal);
+            return false;
         },
         Err(_) => {
-            log(stream, request, response, 0);
+            write_status(stream, "400", immortal);
+            return false;
         },
+        Ok(req) => req,
+    };
+    request.server_name = server_name;
+    let (request_rc, response_rc) = dispatch(request, immortal);
+    finish_response(stream, request_rc, response_rc, served, immortal)
+}
+
+/// Decides whether the connection is kept open after a response, marks the response accordingly
+/// and writes it.
+///
+/// Returns true if the connection should be kept open for another request.
+fn finish_response<T: Transport>(
+    stream: &mut T,
+    request_rc: Rc<RefCell<Request>>,
+    response_rc: Rc<RefCell<Response>>,
+    served: usize,
+    immortal: &Immortal,
+) -> bool {
+    let keep_alive = immortal.keep_alive_enabled()
+        && !immortal.shutdown.is_shutdown()
+        && wants_keep_alive(&mut request_rc.borrow_mut())
+        && (immortal.max_requests_per_connection == 0 || served < immortal.max_requests_per_connection)
+        && response_rc.borrow().header("Connection") != Some("close")
+        && !response_rc.borrow().is_close_delimited();
+
+    if keep_alive {
+        let mut response = response_rc.borrow_mut();
+        response.headers.insert("Connection", "keep-alive".to_string());
+        response.headers.insert("Keep-Alive", format!("timeout={}", immortal.keep_alive_timeout.as_secs()));
+    } else {
+        response_rc.borrow_mut().headers.insert("Connection", "close".to_string());
     }
+
+    stream_write(stream, request_rc, response_rc, immortal) && keep_alive
 }
 
-/// Reads the TcpStream and handles errors while reading
-fn handle_connection(
-    mut stream: TcpStream,
-    session_manager: Arc<SessionManager>,
-    middleware: &Middleware,
-    router: &Router
+/// Runs a request through the middleware and the router, returning it along with the response
+/// they produced
+fn dispatch<'buf>(
+    request: Request<'buf>,
+    immortal: &'buf Immortal,
+) -> (Rc<RefCell<Request<'buf>>>, Rc<RefCell<Response<'buf>>>) {
+    let mut started = Instant::now();
+    let (request_rc, response_rc, route) = run_handlers(request, immortal, |ctx| {
+        immortal.middleware.run(ctx);
+
+        started = Instant::now();
+        match immortal.metrics_path.as_deref() {
+            Some(path) if is_metrics_request(ctx, path) => {
+                ctx.route = Some(path);
+                serve_metrics(ctx, immortal);
+            },
+            _ => immortal.router.call(ctx),
+        }
+    });
+    if let Some(metrics) = &immortal.metrics {
+        metrics.observe(
+            request_rc.borrow().method,
+            route.unwrap_or(metrics::UNMATCHED),
+            response_rc.borrow().code,
+            started.elapsed(),
+        );
+    }
+    (request_rc, response_rc)
+}
+
+/// Returns true if the request asks for the metrics at `path`, which middleware can still refuse
+/// by redirecting
+fn is_metrics_request(ctx: &mut Context, path: &str) -> bool {
+    matches!(ctx.request().method, "GET" | "HEAD")
+        && ctx.request().document == path
+        && !ctx.response().is_redirect()
+}
+
+/// Answers with the metrics in the Prometheus text format
+fn serve_metrics(ctx: &mut Context, immortal: &Immortal) {
+    let mut response = ctx.response_mut();
+    response.body = immortal.metrics.as_ref()
+        .map(Metrics::render)
+        .unwrap_or_default()
+        .into_bytes();
+    response.headers.insert("Content-Type", "text/plain; version=0.0.4; charset=utf-8".to_string());
+}
+
+/// Builds the context for a request and runs `handlers` on it, returning the route they matched
+/// along with the request and response
+fn run_handlers<'buf, F>(
+    request: Request<'buf>,
+    immortal: &'buf Immortal,
+    handlers: F,
+) -> (Rc<RefCell<Request<'buf>>>, Rc<RefCell<Response<'buf>>>, Option<&'buf str>) where F: FnOnce(&mut Context<'buf>) {
+    let request_rc = Rc::new(RefCell::new(request));
+    let mut session_id = Uuid::nil();
+    let mut response = Response::new(request_rc.clone(), immortal.session_manager.clone(), &mut session_id);
+    for (key, value) in &immortal.default_headers {
+        response.headers.insert(key, value.clone());
+    }
+    let response_rc = Rc::new(RefCell::new(response));
+    let mut ctx = Context::new(request_rc.clone(), response_rc.clone(), session_id, immortal.session_manager.clone())
+        .with_state(immortal.state.clone());
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| handlers(&mut ctx)));
+    if let Err(payload) = outcome {
+        recover(&request_rc, &response_rc, payload.as_ref(), ctx.route, immortal);
+    }
+    request_rc.borrow_mut().session_id = ctx.session_id;
+
+    (request_rc, response_rc, ctx.route)
+}
+
+/// Reports a panic in a handler and replaces whatever response it left behind with a
+/// `500 Internal Server Error`, which is written as usual
+///
+/// Borrows of the request and response are released while unwinding, so they can be used again.
+fn recover<'buf>(
+    request_rc: &Rc<RefCell<Request<'buf>>>,
+    response_rc: &Rc<RefCell<Response<'buf>>>,
+    payload: &(dyn std::any::Any + Send),
+    route: Option<&str>,
+    immortal: &'buf Immortal,
 ) {
-    let peer_addr = match stream.peer_addr() {
-        Ok(addr) => Some(addr),
-        Err(_) => None,
-    };
-    let mut buf: [u8; 4096] = [0; 4096];
-    let read_sz = match stream.read(&mut buf) {
-        Err(_e) => {
-            debug_eprintln!("{}", _e);
-            let _ = stream.shutdown(std::net::Shutdown::Both);
-            return;
-        },
-        Ok(sz) => sz,
+    let request = request_rc.borrow();
+    let report = HandlerPanic {
+        method: request.method,
+        document: request.document,
+        route,
+        message: recovery::message(payload),
     };
+    immortal.report_panic(&report);
 
-    match read_sz {
-        //0 => break,
-        0 => {
-            let _ = stream.shutdown(std::net::Shutdown::Both);
-            return
-        },
-        _ => {
-            let request = match Request::new(&buf, peer_addr.as_ref()) {
-                Err(RequestError::ProtoVersionInvalid(_)) => {
-                    let request = Rc::new(RefCell::new(Request::bad()));
-                    let response = Rc::new(RefCell::new(Response::bad()));
-                    response.borrow_mut().code = "505";
-                    stream_write(&mut stream, request, response);
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    return;
-                },
-                Err(_) => {
-                    let request = Rc::new(RefCell::new(Request::bad()));
-                    let response = Rc::new(RefCell::new(Response::bad()));
-                    stream_write(&mut stream, request, response);
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    return;
-                },
-                Ok(req) => req,
+    let mut response = Response::bad();
+    for (key, value) in &immortal.default_headers {
+        response.headers.insert(key, value.clone());
+    }
+    response.code = "500";
+    response.method = request.method;
+    response.protocol = response_rc.borrow().protocol;
+    response.body = b"<h1>500: Internal Server Error</h1>".to_vec();
+    *response_rc.borrow_mut() = response;
+}
+
+/// Reads requests from the stream and handles errors while reading
+///
+/// The connection is kept open for further requests until the client asks for it to be closed,
+/// it sits idle for longer than the keep-alive timeout, or it reaches the maximum amount of
+/// requests allowed per connection. During a shutdown, no further requests are read.
+fn handle_connection<T: Transport>(mut stream: T, guard: ConnectionGuard, immortal: &Immortal) {
+    let peer_addr = stream.peer_addr();
+    let server_name = stream.server_name().map(|name| name.to_string());
+    let mut buf: Vec<u8> = Vec::with_capacity(4096);
+    let mut served: usize = 0;
+
+    if stream.set_write_timeout(Some(immortal.write_timeout)).is_err() {
+        return;
+    }
+
+    loop {
+        if immortal.shutdown.is_shutdown() {
+            break;
+        }
+
+        // subsequent requests on a persistent connection may only idle for so long
+        let idle_timeout = match served {
+            0 => immortal.header_read_timeout,
+            _ => immortal.keep_alive_timeout,
+        };
+
+        guard.set_idle(true);
+        let frame = read_frame(&mut stream, &mut buf, idle_timeout, peer_addr.as_ref(), server_name.as_deref(), immortal);
+        guard.set_idle(false);
+
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                if let ReadError::Framing(_e) = &e {
+                    debug_eprintln!("{}", _e);
+                }
+                write_status(&mut stream, e.status_code(), immortal);
+                break;
+            },
+        };
+
+        #[cfg(feature = "h2c")]
+        if served == 0 && buf.starts_with(b"PRI * HTTP/2.0\r\n\r\n") {
+            h2::serve(&mut stream, std::mem::take(&mut buf), None, &guard, immortal);
+            break;
+        }
+        #[cfg(feature = "h2c")]
+        if let Some(settings) = h2::upgrade_settings(&buf[..frame.head_len]) {
+            let len = frame.len();
+            let head = buf[..frame.head_len].to_vec();
+            let body = match frame.decoded_body {
+                Some(body) => body,
+                None => buf[frame.head_len..len].to_vec(),
             };
+            buf.drain(..len);
+            let upgrade = h2::Upgrade { head, body, settings };
+            h2::serve(&mut stream, std::mem::take(&mut buf), Some(upgrade), &guard, immortal);
+            break;
+        }
 
-            let request_rc = Rc::new(RefCell::new(request));
-            let mut session_id = Uuid::nil();
-            let response = Response::new(request_rc.clone(), session_manager.clone(), &mut session_id);
-            let response_rc = Rc::new(RefCell::new(response));
-            let mut ctx = Context::new(request_rc.clone(), response_rc.clone(), session_id, session_manager.clone());
+        served += 1;
 
-            middleware.run(&mut ctx);
-            router.call(&mut ctx);
+        let keep_alive = serve_request(&mut stream, &buf, &frame, peer_addr.as_ref(), server_name.as_deref(), served, immortal);
+        buf.drain(..frame.len());
 
-            stream_write(&mut stream, request_rc, response_rc);
-        },
-    };
-    let _ = stream.shutdown(std::net::Shutdown::Both);
+        if !keep_alive {
+            break;
+        }
+    }
+    stream.shutdown();
+}
+
+/// Answers a connection that arrived while the server was at capacity with
+/// `503 Service Unavailable` and closes it
+fn shed_connection<T: Transport>(mut stream: T, immortal: &Immortal) {
+    // the accept loop must not be held up by a client that does not read
+    if stream.set_write_timeout(Some(Duration::from_secs(1))).is_err() {
+        return;
+    }
+    write_unavailable(&mut stream, immortal);
+    stream.shutdown();
+}
+
+/// Writes `503 Service Unavailable`, asking the client to try again shortly
+fn write_unavailable<T: Transport>(stream: &mut T, immortal: &Immortal) {
+    let request = Rc::new(RefCell::new(Request::bad()));
+    let response = Rc::new(RefCell::new(Response::bad()));
+    response.borrow_mut().code = "503";
+    response.borrow_mut().headers.insert("Retry-After", "1".to_string());
+    stream_write(stream, request, response, immortal);
+}
+
+/// Writes an error response with the status `code` for a request that could not be served
+fn write_status<T: Transport>(stream: &mut T, code: &'static str, immortal: &Immortal) -> bool {
+    let request = Rc::new(RefCell::new(Request::bad()));
+    let response = Rc::new(RefCell::new(Response::bad()));
+    response.borrow_mut().code = code;
+    stream_write(stream, request, response, immortal)
 }
 
 /// Immortal middleware and routing configuration, as well as the session manager.
-#[derive(Default)]
 pub struct Immortal {
     middleware: Middleware,
+    /// Middleware that inspects requests expecting `100 Continue` before their body is read
+    header_middleware: Middleware,
     router: Router,
     session_manager: Arc<SessionManager>,
     #[allow(dead_code)]
     session_prune_task: Option<(JoinHandle<()>, Arc<AtomicBool>)>,
+    /// Stops the server when triggered
+    shutdown: ShutdownHandle,
+    /// How long in-flight requests are given to finish during a shutdown
+    shutdown_timeout: Duration,
+    /// Connections that are currently being served
+    connections: Arc<Connections>,
+    /// Certificate served by TLS listeners
+    #[cfg(feature = "tls")]
+    certificates: tls::CertificateHandle,
+    /// How long a persistent connection may sit idle waiting for its next request
+    keep_alive_timeout: Duration,
+    /// How many requests may be served over a single connection, 0 for no limit
+    max_requests_per_connection: usize,
+    /// Size limits for request heads and bodies
+    limits: Limits,
+    /// How many connections may be served or queued at once, 0 for no limit
+    max_connections: usize,
+    /// Permissions given to the socket file of Unix listeners, the umask decides if unset
+    #[cfg(unix)]
+    unix_socket_mode: Option<u32>,
+    /// How long a client has to send a complete request head
+    header_read_timeout: Duration,
+    /// How long a client has to send a complete request body once the head has arrived
+    body_read_timeout: Duration,
+    /// How long a single write of the response may block for
+    write_timeout: Duration,
+    /// How many threads serve connections, 0 for as many as the system has available
+    threads: usize,
+    /// How many bytes are read from a connection at a time
+    read_buffer_size: usize,
+    /// Headers every response starts out with
+    default_headers: Vec<(String, String)>,
+    /// Receives a record of every response, nothing is logged if unset
+    logger: Option<Arc<dyn Logger>>,
+    /// Request counters and latency histograms, nothing is recorded until metrics are enabled
+    metrics: Option<Metrics>,
+    /// Where the metrics are served, they are not served if unset
+    metrics_path: Option<String>,
+    /// Reports handler panics, they are written to stderr if unset
+    panic_hook: Option<Arc<PanicHook>>,
+    /// Application state handed to every handler
+    state: Arc<State>,
+}
+
+impl Default for Immortal {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[allow(dead_code)]
 impl Immortal {
     /// Construct a new Immortal server
     pub fn new() -> Self {
-        Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    /// Construct a new Immortal server from a `ServerConfig`
+    pub fn with_config(config: ServerConfig) -> Self {
+        let mut default_headers = config.default_headers;
+        if let Some(banner) = config.server_banner {
+            default_headers.push(("Server".to_string(), banner));
+        }
+
+        let session_manager = SessionManager::new(
+            config.session_duration,
+            config.session_inactive_duration,
+            config.session_prune_rate,
+        );
+        let session_manager = Arc::new(session_manager);
+        let connections = Arc::new(Connections::default());
+        let mut immortal = Self {
             middleware: Middleware::new(),
+            header_middleware: Middleware::new(),
             router: Router::new(),
-            session_manager: Arc::new(SessionManager::default()),
+            session_manager,
             session_prune_task: None,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: config.shutdown_timeout,
+            connections,
+            #[cfg(feature = "tls")]
+            certificates: tls::CertificateHandle::new(),
+            keep_alive_timeout: config.keep_alive_timeout,
+            max_requests_per_connection: config.max_requests_per_connection,
+            limits: config.limits,
+            max_connections: config.max_connections,
+            #[cfg(unix)]
+            unix_socket_mode: None,
+            header_read_timeout: config.header_read_timeout,
+            body_read_timeout: config.body_read_timeout,
+            write_timeout: config.write_timeout,
+            threads: config.threads,
+            read_buffer_size: config.read_buffer_size.max(1),
+            default_headers,
+            logger: Some(Arc::new(AccessLogger::stdout(LogFormat::Colored))),
+            metrics: None,
+            metrics_path: None,
+            panic_hook: None,
+            state: Arc::new(State::new()),
+        };
+        if config.sessions {
+            immortal.enable_sessions();
+        }
+        if let Some(path) = config.metrics_path {
+            immortal.enable_metrics(&path);
         }
+        immortal
     }
 
     /// Listens for incoming connections, with as many threads as the system has available for
@@ -203,19 +708,21 @@ impl Immortal {
     ) -> Result<(), ImmortalError> where S: Into<SocketAddr> {
         self.listen_with(
             socket_addr,
-            thread::available_parallelism()
-                .map_err(ImmortalError::Io)?
-                .get(),
+            self.thread_count()?,
         )
     }
 
     /// Listens for incoming connections using a specific amount of threads
     ///
-    /// If `threading` feature is not present, `thread_count` will be ignored
+    /// If `threading` feature is not present, `thread_count` will be ignored and connections are
+    /// interleaved on the current thread by an event loop with the `event-loop` feature, or
+    /// otherwise served one at a time
+    ///
+    /// Returns once the server has been stopped through its `ShutdownHandle` and its in-flight
+    /// requests have finished or run out of time.
     pub fn listen_with<S>(
         &self,
         socket_addr: S,
-        #[allow(unused_variables)]
         thread_count: usize
     ) -> Result<(), ImmortalError> where S: Into<SocketAddr> {
         let socket_addr: SocketAddr = socket_addr.into();
@@ -224,67 +731,369 @@ impl Immortal {
 
         println!("Server starting at: http://{socket_addr}");
 
-        #[cfg(feature = "threading")] 
-        {
-            let thread_pool = rayon::ThreadPoolBuilder::new()
-                .num_threads(thread_count)
-                .build()
-                .map_err(ImmortalError::Tpbe)?;
-
-            let _ = thread_pool.scope(|scope| -> Result<(), ImmortalError> {
-
-                loop {
-                    let (stream, _peer_addr) = listener.accept()
-                        .map_err(ImmortalError::AcceptError)?;
-
-                    scope.spawn(|_s| {
-                        handle_connection(
-                            stream,
-                            self.session_manager.clone(),
-                            &self.middleware,
-                            &self.router,
-                        );
+        self.serve_with(listener, thread_count)
+    }
+
+    /// Serves connections from an already bound `listener`, with as many threads as the system
+    /// has available for parallelism
+    ///
+    /// This allows the socket to be set up elsewhere, such as by systemd socket activation
+    /// through `transport::listener_from_env`.
+    pub fn serve(&self, listener: TcpListener) -> Result<(), ImmortalError<'_>> {
+        self.serve_with(
+            listener,
+            self.thread_count()?,
+        )
+    }
+
+    /// Serves connections from an already bound `listener` using a specific amount of threads
+    ///
+    /// If `threading` feature is not present, `thread_count` will be ignored and connections are
+    /// interleaved on the current thread by an event loop with the `event-loop` feature, or
+    /// otherwise served one at a time
+    pub fn serve_with(&self, listener: TcpListener, thread_count: usize) -> Result<(), ImmortalError<'_>> {
+        self.serve_plain(listener, thread_count)
+    }
+
+    /// Serves the requests sent over a single connection on the current thread, returning once
+    /// the connection is closed
+    ///
+    /// `stream` can be anything that reads and writes bytes, pass `&mut stream` to keep using it
+    /// afterwards.
+    pub fn serve_connection<S>(&self, stream: S) where S: Read + Write {
+        self.serve_transport(transport::Plain(stream));
+    }
+
+    /// Serves the requests sent over a single connection on the current thread, making use of
+    /// its peer address and timeouts
+    pub fn serve_transport<T>(&self, stream: T) where T: Transport {
+        // there is no way to interrupt an arbitrary stream, a shutdown waits for it to finish
+        let guard = self.connections.register(Box::new(|| {}));
+        handle_connection(stream, guard, self);
+    }
+
+    /// Listens for incoming TLS connections, with as many threads as the system has available
+    /// for parallelism
+    ///
+    /// `cert_pem` and `key_pem` are the PEM encoded certificate chain and private key, they can be
+    /// replaced while the server is running through `certificate_handle`.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls<S>(
+        &self,
+        socket_addr: S,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<(), ImmortalError<'_>> where S: Into<SocketAddr> {
+        self.listen_tls_with(
+            socket_addr,
+            cert_pem,
+            key_pem,
+            self.thread_count()?,
+        )
+    }
+
+    /// Listens for incoming TLS connections using a specific amount of threads
+    ///
+    /// If `threading` feature is not present, `thread_count` will be ignored and connections are
+    /// served one at a time
+    #[cfg(feature = "tls")]
+    pub fn listen_tls_with<S>(
+        &self,
+        socket_addr: S,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        thread_count: usize
+    ) -> Result<(), ImmortalError<'_>> where S: Into<SocketAddr> {
+        let socket_addr: SocketAddr = socket_addr.into();
+        let listener = TcpListener::bind(socket_addr)
+            .map_err(ImmortalError::Io)?;
+
+        println!("Server starting at: https://{socket_addr}");
+
+        self.serve_tls_with(listener, cert_pem, key_pem, thread_count)
+    }
+
+    /// Serves TLS connections from an already bound `listener`, with as many threads as the
+    /// system has available for parallelism
+    #[cfg(feature = "tls")]
+    pub fn serve_tls(&self, listener: TcpListener, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), ImmortalError<'_>> {
+        self.serve_tls_with(
+            listener,
+            cert_pem,
+            key_pem,
+            self.thread_count()?,
+        )
+    }
+
+    /// Serves TLS connections from an already bound `listener` using a specific amount of threads
+    ///
+    /// If `threading` feature is not present, `thread_count` will be ignored and connections are
+    /// served one at a time
+    #[cfg(feature = "tls")]
+    pub fn serve_tls_with(
+        &self,
+        listener: TcpListener,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        thread_count: usize
+    ) -> Result<(), ImmortalError<'_>> {
+        self.certificates.reload(cert_pem, key_pem)
+            .map_err(ImmortalError::Tls)?;
+        let config = self.certificates.server_config()
+            .map_err(ImmortalError::Tls)?;
+
+        // a plaintext 503 means nothing to a client expecting a handshake, so it is just closed
+        self.accept_loop(listener, thread_count, drop, |stream, guard| {
+            match tls::TlsStream::accept(stream, config.clone(), self.header_read_timeout) {
+                Ok(stream) => handle_connection(stream, guard, self),
+                Err(_e) => {
+                    debug_eprintln!("ERROR: TLS handshake failed: {_e}");
+                },
+            }
+        })
+    }
+
+    /// Returns a handle that can replace the certificate of a TLS listener while it is running
+    #[cfg(feature = "tls")]
+    pub fn certificate_handle(&self) -> tls::CertificateHandle {
+        self.certificates.clone()
+    }
+
+    /// Listens for incoming connections on a Unix domain socket at `path`, with as many threads
+    /// as the system has available for parallelism
+    #[cfg(unix)]
+    pub fn listen_unix<P>(&self, path: P) -> Result<(), ImmortalError<'_>> where P: AsRef<Path> {
+        self.listen_unix_with(
+            path,
+            self.thread_count()?,
+        )
+    }
+
+    /// Listens for incoming connections on a Unix domain socket at `path` using a specific
+    /// amount of threads
+    ///
+    /// A socket file left behind by a server that is no longer running is replaced, and the
+    /// socket file is removed once the server stops.
+    #[cfg(unix)]
+    pub fn listen_unix_with<P>(
+        &self,
+        path: P,
+        thread_count: usize
+    ) -> Result<(), ImmortalError<'_>> where P: AsRef<Path> {
+        let path = path.as_ref();
+        transport::remove_stale_socket(path)
+            .map_err(ImmortalError::Io)?;
+        let listener = UnixListener::bind(path)
+            .map_err(ImmortalError::Io)?;
+        if let Some(mode) = self.unix_socket_mode {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+                let _ = fs::remove_file(path);
+                return Err(ImmortalError::Io(e));
+            }
+        }
+
+        println!("Server starting at: unix:{}", path.display());
+
+        let result = self.serve_plain(listener, thread_count);
+        let _ = fs::remove_file(path);
+        result
+    }
+
+    /// Serves plaintext connections from `listener` on a pool of `thread_count` threads
+    ///
+    /// Without `threading`, connections are served one at a time unless the `event-loop` feature
+    /// interleaves them. HTTP/2 connections are served with blocking reads and writes, so `h2c`
+    /// keeps this even with `event-loop`.
+    #[cfg(any(not(unix), not(feature = "event-loop"), feature = "threading", feature = "h2c"))]
+    fn serve_plain<L>(&self, listener: L, thread_count: usize) -> Result<(), ImmortalError<'_>> where
+        L: Listener + Sync,
+    {
+        self.accept_loop(listener, thread_count, |stream| shed_connection(stream, self), |stream, guard| {
+            handle_connection(stream, guard, self);
+        })
+    }
+
+    /// Serves plaintext connections from `listener` with an event loop on the current thread, so
+    /// that a slow client does not hold up the others
+    ///
+    /// Streamed response bodies are buffered before they are sent.
+    #[cfg(all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c")))]
+    fn serve_plain<L>(&self, listener: L, _thread_count: usize) -> Result<(), ImmortalError<'_>> where
+        L: Listener + event_loop::Pollable,
+        L::Stream: event_loop::Pollable,
+    {
+        event_loop::serve(self, listener)
+    }
+
+    /// Accepts connections from `listener` and passes them to `serve` until the server is shut
+    /// down, then waits for in-flight connections to finish.
+    ///
+    /// Connections beyond the connection limit are passed to `shed` instead of being queued.
+    fn accept_loop<L, R, F>(
+        &self,
+        listener: L,
+        #[allow(unused_variables)]
+        thread_count: usize,
+        shed: R,
+        serve: F
+    ) -> Result<(), ImmortalError<'_>> where
+        L: Listener + Sync,
+        R: Fn(L::Stream) + Sync,
+        F: Fn(L::Stream, ConnectionGuard) + Sync,
+    {
+        #[cfg(feature = "threading")]
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()
+            .map_err(ImmortalError::Tpbe)?;
+
+        // a shutdown unblocks accept() by connecting to the listener
+        self.shutdown.set_waker(Some(listener.waker().map_err(ImmortalError::Io)?));
+
+        #[cfg(feature = "threading")]
+        let accepted = {
+            let serve = &serve;
+            thread_pool.scope(|scope| {
+                let accepted = loop {
+                    let stream = match self.accept_next(&listener) {
+                        Ok(Some(stream)) => stream,
+                        Ok(None) => break Ok(()),
+                        Err(e) => break Err(e),
+                    };
+                    if self.at_capacity() {
+                        shed(stream);
+                        continue;
+                    }
+                    let guard = self.connections.register(L::closer(&stream));
+
+                    scope.spawn(move |_s| {
+                        serve(stream, guard);
                     });
+                };
+
+                // the scope only returns once every connection is done, so they are drained in it
+                self.connections.drain(self.shutdown_timeout);
+                accepted
+            })
+        };
+
+        #[cfg(not(feature = "threading"))]
+        let accepted = {
+            let accepted = loop {
+                let stream = match self.accept_next(&listener) {
+                    Ok(Some(stream)) => stream,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                };
+                if self.at_capacity() {
+                    shed(stream);
+                    continue;
                 }
-            });
+                let guard = self.connections.register(L::closer(&stream));
+
+                serve(stream, guard);
+            };
+
+            self.connections.drain(self.shutdown_timeout);
+            accepted
+        };
+
+        self.shutdown.set_waker(None);
+        accepted
+    }
+
+    /// Accepts the next connection from `listener`, or returns `None` once the server has been
+    /// shut down
+    ///
+    /// Errors that only concern the connection being accepted are skipped over.
+    fn accept_next<L>(&self, listener: &L) -> Result<Option<L::Stream>, ImmortalError<'static>> where
+        L: Listener,
+    {
+        while !self.shutdown.is_shutdown() {
+            match listener.accept_stream() {
+                Ok(_) if self.shutdown.is_shutdown() => break,
+                Ok(stream) => return Ok(Some(stream)),
+                Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted) => continue,
+                Err(e) => return Err(ImmortalError::AcceptError(e)),
+            }
         }
+        Ok(None)
+    }
 
-        #[cfg(not(feature = "threading"))]
-        loop {
-            let (stream, _peer_addr) = listener.accept()
-                .map_err(ImmortalError::AcceptError)?;
-
-            handle_connection(
-                stream,
-                self.session_manager.clone(),
-                &self.middleware,
-                &self.router,
-            );
+    /// The configured amount of threads, or as many as the system has available for parallelism
+    fn thread_count(&self) -> Result<usize, ImmortalError<'static>> {
+        match self.threads {
+            0 => thread::available_parallelism()
+                .map(|threads| threads.get())
+                .map_err(ImmortalError::Io),
+            threads => Ok(threads),
         }
+    }
 
-        #[cfg(feature = "threading")] 
-        Ok(())
+    /// Returns true if no more connections may be served until some finish
+    fn at_capacity(&self) -> bool {
+        self.max_connections != 0 && self.connections.len() >= self.max_connections
+    }
+
+    /// Returns a handle that can stop the server from another thread once it is listening
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Sets how long in-flight requests are given to finish during a shutdown before their
+    /// connections are closed
+    pub fn set_shutdown_timeout(&mut self, duration: Duration) {
+        self.shutdown_timeout = duration;
     }
 
     /// Pass a buffer through the HTTP implementation without listening on a port or dispatching
     /// tasks to threads.
+    ///
+    /// Pipelined requests in the buffer are answered in order, their responses are returned back
+    /// to back.
     pub fn process_buffer(&mut self, request_buffer: &[u8]) -> Vec<u8> {
-        let request = match Request::from_slice(request_buffer) {
-            Err(_) => return Response::bad().serialize(),
-            Ok(req) => req,
-        };
-
-        let request_rc = Rc::new(RefCell::new(request));
-        let mut session_id = Uuid::nil();
-        let response = Response::new(request_rc.clone(), self.session_manager.clone(), &mut session_id);
-        let response_rc = Rc::new(RefCell::new(response));
-        let mut ctx = Context::new(request_rc.clone(), response_rc.clone(), session_id, self.session_manager.clone());
+        let mut responses = Vec::new();
+        let mut buf = request_buffer;
+        loop {
+            // incomplete requests are parsed as they are, without a body
+            let frame = match framing::frame(buf, &self.limits) {
+                Err(e) => {
+                    let mut response = Response::bad();
+                    response.code = e.status_code();
+                    responses.append(&mut response.serialize());
+                    return responses;
+                },
+                Ok(frame) => frame,
+            };
+            let consumed = frame.as_ref().map_or(buf.len(), Frame::len);
+            let request = match &frame {
+                None => Request::from_slice(buf),
+                Some(frame) => frame_request(buf, frame, None),
+            };
+            let request = match request {
+                Err(_) => {
+                    responses.append(&mut Response::bad().serialize());
+                    return responses;
+                },
+                Ok(req) => req,
+            };
 
-        self.middleware.run(&mut ctx);
-        self.router.call(&mut ctx);
+            let (request_rc, response_rc) = dispatch(request, self);
 
-        let data = response_rc.borrow_mut().serialize();
-        data
+            // pipelined requests are answered in order until the buffer runs out or one of them
+            // asks for the connection to be closed
+            buf = &buf[consumed..];
+            let last = buf.iter().all(|c| matches!(c, b'\r' | b'\n'))
+                || !wants_keep_alive(&mut request_rc.borrow_mut())
+                || response_rc.borrow().header("Connection") == Some("close");
+            if last {
+                response_rc.borrow_mut().headers.insert("Connection", "close".to_string());
+            }
+            responses.append(&mut response_rc.borrow_mut().serialize());
+            if last {
+                return responses;
+            }
+        }
     }
 
     /// Adds middleware that gets executed just before the router.
@@ -292,16 +1101,99 @@ impl Immortal {
     /// if a middleware handler produces a redirect, all of the following middleware handlers are
     /// skipped and the redirect is yielded, if middleware produces a redirect, the router is
     /// bypassed and custom routes do not run. 
-    pub fn add_middleware(&mut self, func: Handler) {
+    pub fn add_middleware<F>(&mut self, func: F) where F: Fn(&mut Context) + Send + Sync + 'static {
         self.middleware.push(func);
     }
 
-    /// Calls into the router to register a function
-    /// Returns true if a route was registered
-    pub fn register(&mut self, method: &str, route: &str, func: Handler) -> bool {
+    /// Adds middleware that inspects the head of a request sent with `Expect: 100-continue`, before
+    /// the client is told to send the body.
+    ///
+    /// The request has no body yet. A handler rejects the request by setting a status other than
+    /// 200, such as 401 or 413, which is sent as the final response without reading the body.
+    /// Otherwise `100 Continue` is sent and the request goes on to the regular middleware once
+    /// its body has arrived.
+    pub fn add_header_middleware<F>(&mut self, func: F) where F: Fn(&mut Context) + Send + Sync + 'static {
+        self.header_middleware.push(func);
+    }
+
+    /// Replaces the logger that receives a record of every response, the default writes colored
+    /// lines to stdout
+    pub fn set_logger<L>(&mut self, logger: L) where L: Logger + 'static {
+        self.logger = Some(Arc::new(logger));
+    }
+
+    /// Stops responses from being logged
+    pub fn disable_logging(&mut self) {
+        self.logger = None;
+    }
+
+    /// Replaces what is done when a middleware or route handler panics, the default writes the
+    /// request and panic message to stderr
+    ///
+    /// The client is answered with `500 Internal Server Error` either way.
+    pub fn set_panic_hook<F>(&mut self, hook: F) where F: Fn(&HandlerPanic) + Send + Sync + 'static {
+        self.panic_hook = Some(Arc::new(hook));
+    }
+
+    /// Passes a handler panic to the panic hook
+    fn report_panic(&self, report: &HandlerPanic) {
+        match &self.panic_hook {
+            None => recovery::default_hook(report),
+            Some(hook) => hook(report),
+        }
+    }
+
+    /// Serves the metrics in the Prometheus text format to `GET` requests for `path`, after the
+    /// middleware has run so it can guard them
+    ///
+    /// Requests are only recorded from the first time metrics are enabled onwards.
+    pub fn enable_metrics(&mut self, path: &str) {
+        if self.metrics.is_none() {
+            self.metrics = Some(Metrics::new(self.connections.clone(), self.session_manager.clone()));
+        }
+        self.metrics_path = Some(path.to_string());
+    }
+
+    /// Stops serving the metrics, they are still recorded
+    pub fn disable_metrics(&mut self) {
+        self.metrics_path = None;
+    }
+
+    /// Returns the request counters and latency histograms recorded so far, `None` if metrics
+    /// were never enabled
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Makes `value` available to every handler and middleware through `Context::state`, by its
+    /// type. A later value of the same type replaces it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state is still shared with a `Context` that outlived its request, state
+    /// should be managed before serving.
+    pub fn manage<T>(&mut self, value: T) where T: Send + Sync + 'static {
+        Arc::get_mut(&mut self.state)
+            .expect("state is still shared with a context")
+            .manage(value);
+    }
+
+    /// Calls into the router to register a function or closure
+    /// Fails if the route clashes with one already registered for the method
+    pub fn register<F>(&mut self, method: &str, route: &str, func: F) -> Result<(), RouteError>
+    where F: Fn(&mut Context) + Send + Sync + 'static {
         self.router.register(method, route, func)
     }
 
+    /// Registers an async function for a route, served by `listen_async` after the middleware
+    /// has run. The blocking listeners answer these routes with the router's fallback.
+    /// Fails if the route clashes with one already registered for the method
+    #[cfg(feature = "tokio")]
+    pub fn register_async<F>(&mut self, method: &str, route: &str, func: F) -> Result<(), RouteError>
+    where F: for<'a> Fn(&'a mut runtime::AsyncContext) -> runtime::BoxFuture<'a> + Send + Sync + 'static {
+        self.router.register_async(method, route, func)
+    }
+
     /// Calls into the router to unregister a function
     /// Returns true if a route was unregistered
     pub fn unregister(&mut self, method: &str, route: &str) -> bool {
@@ -310,8 +1202,73 @@ impl Immortal {
 
     /// Registers the fallback function for when a request is not caught by the router
     /// or for if you want to handle all requests manually
-    pub fn fallback(&mut self, func: Handler) {
-        self.router.fallback = func;
+    pub fn fallback<F>(&mut self, func: F) where F: Fn(&mut Context) + Send + Sync + 'static {
+        self.router.fallback = Arc::new(func);
+    }
+
+    /// Sets how long a persistent connection may sit idle waiting for its next request before it
+    /// is closed, a zero duration disables keep-alive and closes every connection after one
+    /// response.
+    pub fn set_keep_alive_timeout(&mut self, duration: Duration) {
+        self.keep_alive_timeout = duration;
+    }
+
+    /// Sets how many requests may be served over a single connection before it is closed, 0 for
+    /// no limit.
+    pub fn set_max_requests_per_connection(&mut self, max_requests: usize) {
+        self.max_requests_per_connection = max_requests;
+    }
+
+    /// Sets the maximum size of a request line and its headers, larger requests are answered with
+    /// `431 Request Header Fields Too Large`
+    pub fn set_max_header_size(&mut self, size: usize) {
+        self.limits.max_header_size = size;
+    }
+
+    /// Sets the maximum size of a request body, larger requests are answered with
+    /// `413 Payload Too Large`
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.limits.max_body_size = size;
+    }
+
+    /// Sets how many connections may be served or queued at once, 0 for no limit
+    ///
+    /// Connections beyond the limit are answered with `503 Service Unavailable` straight away,
+    /// TLS connections are closed without an answer.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// Sets the permissions given to the socket file of Unix listeners, such as `0o660` to let a
+    /// reverse proxy in the same group connect
+    #[cfg(unix)]
+    pub fn set_unix_socket_mode(&mut self, mode: u32) {
+        self.unix_socket_mode = Some(mode);
+    }
+
+    /// Sets how long a client has to send a complete request head, slower clients are answered
+    /// with `408 Request Timeout`
+    ///
+    /// This also bounds how long a new connection may wait before sending its first request, and
+    /// how long a TLS handshake may take.
+    pub fn set_header_read_timeout(&mut self, duration: Duration) {
+        self.header_read_timeout = duration;
+    }
+
+    /// Sets how long a client has to send a complete request body once the head has arrived,
+    /// slower clients are answered with `408 Request Timeout`
+    pub fn set_body_read_timeout(&mut self, duration: Duration) {
+        self.body_read_timeout = duration;
+    }
+
+    /// Sets how long a single write of a response may block for before the connection is dropped
+    pub fn set_write_timeout(&mut self, duration: Duration) {
+        self.write_timeout = duration;
+    }
+
+    /// Returns true if connections may be reused for more than one request
+    fn keep_alive_enabled(&self) -> bool {
+        !self.keep_alive_timeout.is_zero() && self.max_requests_per_connection != 1
     }
 
     /// sets the maximum duration that a session may be allowed to persist for
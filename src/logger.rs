@@ -0,0 +1,308 @@
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use colored::*;
+use uuid::Uuid;
+
+use crate::transport::PeerAddr;
+use crate::util::{code_color, strip_for_terminal};
+
+/// What is known about a request once its response has been sent
+#[derive(Debug, Clone)]
+pub struct AccessRecord<'a> {
+    /// When the response was sent
+    pub time: DateTime<Utc>,
+    /// How long the request took to serve, from when it had arrived in full
+    pub duration: Duration,
+    pub peer_addr: Option<&'a PeerAddr>,
+    pub method: &'a str,
+    pub document: &'a str,
+    pub query: &'a str,
+    pub version: &'a str,
+    pub code: &'a str,
+    /// Bytes written for the response, including its head
+    pub sent: usize,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    /// The session the request belongs to, if sessions are enabled
+    pub session_id: Option<Uuid>,
+}
+
+/// Receives a record of every response the server sends
+pub trait Logger: Send + Sync {
+    fn log(&self, record: &AccessRecord);
+}
+
+/// The line formats `AccessLogger` can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The colored terminal format
+    Colored,
+    /// The Common Log Format
+    Common,
+    /// The Combined Log Format, the Common Log Format followed by the referer and user agent
+    Combined,
+    /// A JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    /// Renders a record as a single line, without the line ending
+    pub fn format(&self, record: &AccessRecord) -> String {
+        match self {
+            LogFormat::Colored => colored_line(record),
+            LogFormat::Common => common_line(record),
+            LogFormat::Combined => format!("{} \"{}\" \"{}\"",
+                common_line(record),
+                record.referer.map(escape_quoted).unwrap_or_else(|| "-".to_string()),
+                record.user_agent.map(escape_quoted).unwrap_or_else(|| "-".to_string())),
+            LogFormat::Json => json_line(record),
+        }
+    }
+}
+
+/// Writes a line per response in one of the built in formats
+pub struct AccessLogger {
+    format: LogFormat,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLogger {
+    /// Logs to stdout
+    pub fn stdout(format: LogFormat) -> Self {
+        Self::to_writer(format, io::stdout())
+    }
+
+    /// Logs to any writer, such as a `RotatingFile`
+    pub fn to_writer<W>(format: LogFormat, writer: W) -> Self where W: Write + Send + 'static {
+        Self {
+            format,
+            output: Mutex::new(Box::new(writer)),
+        }
+    }
+}
+
+impl Logger for AccessLogger {
+    fn log(&self, record: &AccessRecord) {
+        let mut line = self.format.format(record);
+        line.push('\n');
+        // a poisoned lock only means another line was cut short
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = output.write_all(line.as_bytes());
+        let _ = output.flush();
+    }
+}
+
+/// A log file that is rotated once it grows too large or has been written to for too long
+///
+/// Rotating renames the file to `<path>.1`, shifting older files up to `<path>.<keep>` and
+/// removing the oldest.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+    /// When the current file was opened
+    opened: Instant,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, it is not rotated until a maximum size or age is set
+    pub fn open<P>(path: P) -> io::Result<Self> where P: AsRef<Path> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size: None,
+            max_age: None,
+            keep: 5,
+        })
+    }
+
+    /// Rotates the file before it would grow past `bytes`
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates the file once it has been open for `age`
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// How many rotated files are kept, 0 discards the file when it is rotated
+    pub fn keep(mut self, files: usize) -> Self {
+        self.keep = files;
+        self
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        let too_large = self.max_size.is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        let too_old = self.max_age.is_some_and(|max| self.opened.elapsed() >= max);
+        too_large || too_old
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The address of the client without its port, which is what access logs usually show
+fn client(peer_addr: Option<&PeerAddr>) -> Option<String> {
+    match peer_addr {
+        None => None,
+        Some(PeerAddr::Tcp(addr)) => Some(addr.ip().to_string()),
+        Some(addr) => Some(addr.to_string()),
+    }
+}
+
+fn colored_line(record: &AccessRecord) -> String {
+    let remote_socket = match client(record.peer_addr) {
+        None => "<no socket>".red().bold(),
+        Some(client) => client.normal(),
+    };
+
+    let date_time = record.time.format("%a, %d %b %Y %H:%M:%S").to_string();
+    let time_stamp = format!("[{:<17}]",
+                             format!("{}.{}",
+                                record.time.timestamp(),
+                                record.time.timestamp_subsec_micros())
+                            .bright_blue());
+
+    let method = match record.method {
+        "" => "<no method>".red().bold(),
+        method => strip_for_terminal(method).normal(),
+    };
+
+    let document = match record.document {
+        "" => "<no document>".red().bold(),
+        document => strip_for_terminal(document).normal(),
+    };
+
+    let user_agent = match record.user_agent {
+        None => "<no user-agent>".red().bold(),
+        Some(thing) => strip_for_terminal(thing).normal(),
+    };
+
+    format!("{}  {}  {}  {}\t{}  {}\t{}\t{}",
+            date_time,
+            time_stamp,
+            remote_socket,
+            method,
+            code_color(record.code),
+            record.sent,
+            if record.query.is_empty() {
+                document
+            } else {
+                format!("{}?{}", document, strip_for_terminal(record.query)).normal()
+            },
+            user_agent)
+}
+
+fn common_line(record: &AccessRecord) -> String {
+    let target = match record.query {
+        "" => record.document.to_string(),
+        query => format!("{}?{}", record.document, query),
+    };
+    let request_line = match record.method {
+        "" => "-".to_string(),
+        method => escape_quoted(&format!("{} {} HTTP/{}", method, target, record.version)),
+    };
+    let sent = match record.sent {
+        0 => "-".to_string(),
+        sent => sent.to_string(),
+    };
+    format!("{} - - [{}] \"{}\" {} {}",
+            client(record.peer_addr).unwrap_or_else(|| "-".to_string()),
+            record.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            request_line,
+            record.code,
+            sent)
+}
+
+fn json_line(record: &AccessRecord) -> String {
+    let optional = |value: Option<&str>| value.map(json_string).unwrap_or_else(|| "null".to_string());
+    format!("{{\"time\":{},\"peer\":{},\"method\":{},\"document\":{},\"query\":{},\"version\":{},\"status\":{},\"bytes\":{},\"duration_us\":{},\"user_agent\":{},\"referer\":{},\"session_id\":{}}}",
+            json_string(&record.time.to_rfc3339()),
+            optional(client(record.peer_addr).as_deref()),
+            json_string(record.method),
+            json_string(record.document),
+            json_string(record.query),
+            json_string(record.version),
+            record.code.parse::<u16>().map(|code| code.to_string()).unwrap_or_else(|_| json_string(record.code)),
+            record.sent,
+            record.duration.as_micros(),
+            optional(record.user_agent),
+            optional(record.referer),
+            optional(record.session_id.map(|id| id.to_string()).as_deref()))
+}
+
+/// Escapes a value for a double quoted field of the Common Log Format
+fn escape_quoted(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,123 @@
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::session::SessionManager;
+use crate::shutdown::Connections;
+
+/// The upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Methods kept as they are in labels, any other method is counted as `OTHER` so clients cannot
+/// grow the amount of series
+const METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// The route label of requests that no registered route matched
+pub const UNMATCHED: &str = "unmatched";
+
+/// What has been recorded for a single method and route
+#[derive(Clone, Default)]
+struct RouteStats {
+    /// Responses by status code
+    statuses: HashMap<String, u64>,
+    /// Observations that fell into each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Request counters and latency histograms, along with the gauges of the server they belong to
+///
+/// Rendered in the Prometheus text format by `render`, which `Immortal::enable_metrics` serves.
+pub struct Metrics {
+    routes: DashMap<(String, String), RouteStats>,
+    connections: Arc<Connections>,
+    session_manager: Arc<SessionManager>,
+}
+
+impl Metrics {
+    pub(crate) fn new(connections: Arc<Connections>, session_manager: Arc<SessionManager>) -> Self {
+        Self {
+            routes: DashMap::new(),
+            connections,
+            session_manager,
+        }
+    }
+
+    /// Records a response to `method` on `route`, which is the registered route that handled it
+    /// rather than the requested document
+    pub fn observe(&self, method: &str, route: &str, code: &str, elapsed: Duration) {
+        let method = match METHODS.contains(&method) {
+            true => method,
+            false => "OTHER",
+        };
+        let mut stats = self.routes.entry((method.to_string(), route.to_string())).or_default();
+        *stats.statuses.entry(code.to_string()).or_default() += 1;
+
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            stats.buckets[bucket] += 1;
+        }
+        stats.sum += seconds;
+        stats.count += 1;
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        // copied out so no shard stays locked while rendering
+        let mut routes: Vec<_> = self.routes.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut out = String::new();
+
+        out.push_str("# HELP immortal_requests_total Responses sent, by method, route and status.\n");
+        out.push_str("# TYPE immortal_requests_total counter\n");
+        for ((method, route), stats) in &routes {
+            let mut statuses: Vec<_> = stats.statuses.iter().collect();
+            statuses.sort();
+            for (code, count) in statuses {
+                let _ = writeln!(out, "immortal_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    escape(method), escape(route), escape(code), count);
+            }
+        }
+
+        out.push_str("# HELP immortal_request_duration_seconds Time spent in the handler of a route.\n");
+        out.push_str("# TYPE immortal_request_duration_seconds histogram\n");
+        for ((method, route), stats) in &routes {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "immortal_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "immortal_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}", stats.count);
+            let _ = writeln!(out, "immortal_request_duration_seconds_sum{{{labels}}} {}", stats.sum);
+            let _ = writeln!(out, "immortal_request_duration_seconds_count{{{labels}}} {}", stats.count);
+        }
+
+        out.push_str("# HELP immortal_connections_in_flight Connections currently open.\n");
+        out.push_str("# TYPE immortal_connections_in_flight gauge\n");
+        let _ = writeln!(out, "immortal_connections_in_flight {}", self.connections.len());
+
+        out.push_str("# HELP immortal_sessions Sessions in the session store.\n");
+        out.push_str("# TYPE immortal_sessions gauge\n");
+        let _ = writeln!(out, "immortal_sessions {}", self.session_manager.session_count());
+
+        out.push_str("# HELP immortal_sessions_pruned_total Sessions removed for expiring or being inactive.\n");
+        out.push_str("# TYPE immortal_sessions_pruned_total counter\n");
+        let _ = writeln!(out, "immortal_sessions_pruned_total {}", self.session_manager.pruned_count());
+        out
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
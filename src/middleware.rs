@@ -1,4 +1,6 @@
 
+use std::sync::Arc;
+
 use crate::router::Handler;
 use crate::context::Context;
 
@@ -19,8 +21,8 @@ impl Middleware {
     }
 
     /// Inserts a handler into the middleware
-    pub fn push(&mut self, func: Handler) {
-        self.middleware.push(func);
+    pub fn push<F>(&mut self, func: F) where F: Fn(&mut Context) + Send + Sync + 'static {
+        self.middleware.push(Arc::new(func));
     }
 
     /// Runs all the middleware on the `ctx`
@@ -0,0 +1,36 @@
+
+use std::any::Any;
+
+/// What is known about a middleware or route handler that panicked
+#[derive(Debug, Clone)]
+pub struct HandlerPanic<'a> {
+    pub method: &'a str,
+    pub document: &'a str,
+    /// The registered route that matched the request, such as `/users/:id`, `None` if the panic
+    /// happened before a route was matched
+    pub route: Option<&'a str>,
+    /// The message the handler panicked with
+    pub message: &'a str,
+}
+
+/// Reports handler panics, set through `Immortal::set_panic_hook`
+pub type PanicHook = dyn Fn(&HandlerPanic) + Send + Sync;
+
+/// Reports a panic on stderr, used unless a hook is set
+pub(crate) fn default_hook(panic: &HandlerPanic) {
+    match panic.route {
+        None => eprintln!("ERROR: handler for {} {} panicked: {}", panic.method, panic.document, panic.message),
+        Some(route) => eprintln!("ERROR: handler for {} {} panicked on {}: {}", panic.method, route, panic.document, panic.message),
+    }
+}
+
+/// Extracts the message from the payload of a panic, which is usually a string
+pub(crate) fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
@@ -1,13 +1,16 @@
 
 use std::fmt::Display;
 use std::str::{self, Utf8Error};
-use std::net::SocketAddr;
+use std::time::Instant;
 use std::error;
 
 use crate::cookie::{Cookie, parse_cookies};
+use crate::framing;
+use crate::transport::PeerAddr;
 use crate::util::*;
 
 use debug_print::{debug_eprintln, debug_println};
+use uuid::Uuid;
 
 
 /// Request contains the request representation that is serialised from the main HTTP request from
@@ -32,7 +35,15 @@ pub struct Request<'buf> {
     content_type: Option<&'buf str>,
     content_length: Option<usize>,
 
-    pub peer_addr: Option<SocketAddr>,
+    pub peer_addr: Option<PeerAddr>,
+    /// The server name the client asked for during the TLS handshake, if the request came in
+    /// over TLS with SNI
+    pub server_name: Option<&'buf str>,
+
+    /// When the request had arrived in full
+    pub(crate) received: Option<Instant>,
+    /// The session the request belongs to, set once it has been handled
+    pub(crate) session_id: Uuid,
 }
 
 #[derive(Debug)]
@@ -56,6 +67,8 @@ pub enum RequestError<'buf> {
     HeadersNotUtf8(Utf8Error),
 
     ContentLengthDiscrepancy {expected: usize, got: usize },
+    ContentLengthInvalid,
+    ContentLengthWithTransferEncoding,
 
     PostParamsMalformed(&'buf [u8]),
 }
@@ -79,12 +92,41 @@ impl<'buf> Request<'buf> {
     }
 
     /// Construct a new request object, parsing the request buffer
+    ///
+    /// The body is cut off at the `Content-Length`, and is empty without one. Anything past it
+    /// belongs to the next request on the connection.
     pub fn new(
         buf: &'buf [u8],
-        peer_addr: Option<&SocketAddr>
+        peer_addr: Option<&PeerAddr>
+    ) -> Result<Self, RequestError<'buf>> {
+        let (request_head, request_body) = request_head_body_split(buf);
+        let request_body = match framing::content_length(request_head) {
+            Err(_) => return Err(RequestError::ContentLengthInvalid),
+            Ok(Some(len)) => request_body.map(|body| &body[..len.min(body.len())]),
+            // chunked bodies are decoded by the framing and passed to `from_head_body`
+            Ok(None) if framing::is_chunked(request_head).unwrap_or(false) => request_body,
+            Ok(None) => request_body.map(|body| &body[..0]),
+        };
+        Self::parse(request_head, request_body, peer_addr)
+    }
+
+    /// Construct a new request object from a request head and a body that was decoded separately,
+    /// such as a body sent with `Transfer-Encoding: chunked`
+    pub fn from_head_body(
+        head: &'buf [u8],
+        body: Option<&'buf [u8]>,
+        peer_addr: Option<&PeerAddr>
     ) -> Result<Self, RequestError<'buf>> {
-        let (mut request_head, request_body) = request_head_body_split(buf);
+        let (request_head, _) = request_head_body_split(head);
+        Self::parse(request_head, body, peer_addr)
+    }
 
+    /// Parses the request line and headers of a request head
+    fn parse(
+        mut request_head: &'buf [u8],
+        request_body: Option<&'buf [u8]>,
+        peer_addr: Option<&PeerAddr>
+    ) -> Result<Self, RequestError<'buf>> {
         // ignore preceding clrf if they exist
         loop {
             request_head = match request_head.strip_prefix(b"\r\n") {
@@ -147,7 +189,7 @@ impl<'buf> Request<'buf> {
             .map_err(RequestError::ProtoVersionNotUtf8)?
             .trim_end_matches(|c| ['\r', '\n', '\0'].contains(&c));
 
-        if version != "1.1" {
+        if version != "1.1" && version != "1.0" {
             debug_eprintln!("ERROR: Invalid version {version}");
             return Err(RequestError::ProtoVersionInvalid(request_line));
         }
@@ -159,6 +201,15 @@ impl<'buf> Request<'buf> {
 
         let headers_len = header_raw_lines.len();
 
+        // a message carrying both lengths could be framed two different ways
+        let has_header = |name: &str| header_raw_lines.iter()
+            .filter_map(|line| line.split_once(':'))
+            .any(|(key, _value)| key.trim().eq_ignore_ascii_case(name));
+        if has_header("Content-Length") && has_header("Transfer-Encoding") {
+            debug_eprintln!("ERROR: Content-Length sent with Transfer-Encoding");
+            return Err(RequestError::ContentLengthWithTransferEncoding);
+        }
+
         // emit a complete Request object
         Ok(Self {
             body,
@@ -176,7 +227,10 @@ impl<'buf> Request<'buf> {
             user_agent: None,
             content_type: None,
             content_length: None,
-            peer_addr: peer_addr.copied(),
+            peer_addr: peer_addr.cloned(),
+            server_name: None,
+            received: Some(Instant::now()),
+            session_id: Uuid::nil(),
         })
     }
     
@@ -225,6 +279,14 @@ impl<'buf> Request<'buf> {
         }
     }
 
+    /// Returns true if the body was sent with `Transfer-Encoding: chunked`, in which case `body`
+    /// holds the decoded body and there is no `Content-Length`
+    pub fn is_chunked(&mut self) -> bool {
+        self.header("Transfer-Encoding")
+            .map(|te| te.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false)
+    }
+
     /// looks up HTTP headers and returns
     /// headers are not parsed until they are needed
     pub fn header(&mut self, key: &str) -> Option<&'buf str> {
@@ -298,8 +360,12 @@ impl<'buf> Request<'buf> {
 
         // if post is empty, go about and parse the POST values from the request body.
         if self.post.is_empty() {
-            // must have a content length
-            if let Some(content_len) = self.content_length() {
+            // must have a content length, or a chunked body which is already decoded to length
+            let content_len = match self.content_length() {
+                None if self.is_chunked() => self.body.map(|body| body.len()),
+                content_len => content_len,
+            };
+            if let Some(content_len) = content_len {
                 // and it must be nonzero
                 if content_len == 0 {
                     return None;
@@ -1,4 +1,6 @@
 
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
 use std::rc::Rc;
 use std::collections::HashMap;
 use std::cell::RefCell;
@@ -15,30 +17,135 @@ use uuid::Uuid;
 
 lazy_static! {
     static ref STATUSES: HashMap<String, String> = HashMap::from([
+            ( "100".to_string(), "CONTINUE".to_string() ),
+            ( "101".to_string(), "SWITCHING PROTOCOLS".to_string() ),
+            ( "102".to_string(), "PROCESSING".to_string() ),
+            ( "103".to_string(), "EARLY HINTS".to_string() ),
             ( "200".to_string(), "OK".to_string() ),
+            ( "201".to_string(), "CREATED".to_string() ),
+            ( "202".to_string(), "ACCEPTED".to_string() ),
+            ( "203".to_string(), "NON-AUTHORITATIVE INFORMATION".to_string() ),
+            ( "204".to_string(), "NO CONTENT".to_string() ),
+            ( "205".to_string(), "RESET CONTENT".to_string() ),
+            ( "206".to_string(), "PARTIAL CONTENT".to_string() ),
+            ( "207".to_string(), "MULTI-STATUS".to_string() ),
+            ( "208".to_string(), "ALREADY REPORTED".to_string() ),
+            ( "226".to_string(), "IM USED".to_string() ),
+            ( "300".to_string(), "MULTIPLE CHOICES".to_string() ),
             ( "301".to_string(), "MOVED PERMANENTLY".to_string() ),
             ( "302".to_string(), "FOUND".to_string() ),
+            ( "303".to_string(), "SEE OTHER".to_string() ),
+            ( "304".to_string(), "NOT MODIFIED".to_string() ),
+            ( "305".to_string(), "USE PROXY".to_string() ),
+            ( "307".to_string(), "TEMPORARY REDIRECT".to_string() ),
             ( "308".to_string(), "PERMANENT REDIRECT".to_string() ),
             ( "400".to_string(), "BAD REQUEST".to_string() ),
             ( "401".to_string(), "UNAUTHORIZED".to_string() ),
+            ( "402".to_string(), "PAYMENT REQUIRED".to_string() ),
             ( "403".to_string(), "FORBIDDEN".to_string() ),
             ( "404".to_string(), "NOT FOUND".to_string() ),
+            ( "405".to_string(), "METHOD NOT ALLOWED".to_string() ),
+            ( "406".to_string(), "NOT ACCEPTABLE".to_string() ),
+            ( "407".to_string(), "PROXY AUTHENTICATION REQUIRED".to_string() ),
+            ( "408".to_string(), "REQUEST TIMEOUT".to_string() ),
+            ( "409".to_string(), "CONFLICT".to_string() ),
+            ( "410".to_string(), "GONE".to_string() ),
             ( "411".to_string(), "LENGTH REQUIRED".to_string() ),
+            ( "412".to_string(), "PRECONDITION FAILED".to_string() ),
             ( "413".to_string(), "PAYLOAD TOO LARGE".to_string() ),
             ( "414".to_string(), "URI TOO LONG".to_string() ),
+            ( "415".to_string(), "UNSUPPORTED MEDIA TYPE".to_string() ),
+            ( "416".to_string(), "RANGE NOT SATISFIABLE".to_string() ),
+            ( "417".to_string(), "EXPECTATION FAILED".to_string() ),
             ( "418".to_string(), "I AM A TEAPOT".to_string() ),
+            ( "421".to_string(), "MISDIRECTED REQUEST".to_string() ),
+            ( "422".to_string(), "UNPROCESSABLE CONTENT".to_string() ),
+            ( "423".to_string(), "LOCKED".to_string() ),
+            ( "424".to_string(), "FAILED DEPENDENCY".to_string() ),
+            ( "425".to_string(), "TOO EARLY".to_string() ),
             ( "426".to_string(), "UPGRADE REQUIRED".to_string() ),
+            ( "428".to_string(), "PRECONDITION REQUIRED".to_string() ),
+            ( "429".to_string(), "TOO MANY REQUESTS".to_string() ),
+            ( "431".to_string(), "REQUEST HEADER FIELDS TOO LARGE".to_string() ),
             ( "451".to_string(), "UNAVAILABLE FOR LEGAL REASONS".to_string() ),
             ( "500".to_string(), "INTERNAL SERVER ERROR".to_string() ),
             ( "501".to_string(), "NOT IMPLEMENTED".to_string() ),
+            ( "502".to_string(), "BAD GATEWAY".to_string() ),
+            ( "503".to_string(), "SERVICE UNAVAILABLE".to_string() ),
+            ( "504".to_string(), "GATEWAY TIMEOUT".to_string() ),
             ( "505".to_string(), "HTTP VERSION NOT SUPPORTED".to_string() ),
+            ( "506".to_string(), "VARIANT ALSO NEGOTIATES".to_string() ),
+            ( "507".to_string(), "INSUFFICIENT STORAGE".to_string() ),
+            ( "508".to_string(), "LOOP DETECTED".to_string() ),
+            ( "510".to_string(), "NOT EXTENDED".to_string() ),
+            ( "511".to_string(), "NETWORK AUTHENTICATION REQUIRED".to_string() ),
         ]);
 }
 
+/// A response body that is produced in chunks while it is written to the client, rather than
+/// being held in memory as a whole.
+pub struct BodyStream<'req> {
+    pub(crate) chunks: Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'req>,
+    /// The length of the whole body, if it is known ahead of time
+    pub length: Option<usize>,
+}
+
+impl Debug for BodyStream<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("length", &self.length)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'req> BodyStream<'req> {
+    /// Creates a body stream from an iterator of chunks
+    pub fn new<I>(chunks: I, length: Option<usize>) -> Self
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: 'req,
+    {
+        Self {
+            chunks: Box::new(chunks.into_iter()),
+            length,
+        }
+    }
+
+    /// Creates a body stream that reads the body out of `reader` until it is exhausted
+    pub fn from_reader<R: Read + 'req>(mut reader: R, length: Option<usize>) -> Self {
+        let mut failed = false;
+        let chunks = std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let mut chunk = vec![0u8; 16 * 1024];
+            loop {
+                match reader.read(&mut chunk) {
+                    Ok(0) => return None,
+                    Ok(sz) => {
+                        chunk.truncate(sz);
+                        return Some(Ok(chunk));
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        failed = true;
+                        return Some(Err(e));
+                    },
+                }
+            }
+        });
+        Self::new(chunks, length)
+    }
+}
+
 #[derive(Debug)]
 pub struct Response<'req> {
     pub body: Vec<u8>,
+    /// Streamed body, sent instead of `body` if it is set
+    pub stream: Option<BodyStream<'req>>,
     pub code: &'req str,
+    /// Reason phrase sent for codes without a standard one, an unknown code without one is
+    /// answered with 500 instead
     pub status: &'req str,
     pub protocol: &'req str,
     pub method: &'req str,
@@ -53,11 +160,9 @@ impl<'req> Response<'req> {
         session_manager: Arc<SessionManager>,
         session_id: &mut Uuid
     ) -> Self {
-        let mut headers: HashMap<&str, String> = HashMap::new();
-
-        // default headers
-        headers.insert("Connection", "close".to_string());
-        headers.insert("Content-Type", "text/html".to_string());
+        // default headers are added by the server, `Connection` is decided by the connection
+        // handler
+        let headers: HashMap<&str, String> = HashMap::new();
 
         let sm_is_enabled = session_manager.is_enabled();
         if sm_is_enabled {
@@ -89,11 +194,18 @@ impl<'req> Response<'req> {
             cookies.push(cookie);
         }
 
+        // answer in the version the client spoke
+        let protocol = match req.borrow().version {
+            "1.0" => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        };
+
         Self {
             body: vec![],
+            stream: None,
             code: "200",
-            status: "OK",
-            protocol: "HTTP/1.1",
+            status: "",
+            protocol,
             method: req.borrow_mut().method,
             headers,
             cookies,
@@ -110,6 +222,7 @@ impl<'req> Response<'req> {
 
         Self {
             body: vec![],
+            stream: None,
             code: "400",
             status: "BAD REQUEST",
             protocol: "HTTP/1.1",
@@ -120,25 +233,128 @@ impl<'req> Response<'req> {
     }
 
     /// Generates the serial data for an HTTP response using the object internal state
+    ///
+    /// If a body stream is set, it is read to the end into `body` first.
     pub fn serialize(&mut self) -> Vec<u8> {
-        let mut serialized = vec![];
+        if let Some(stream) = self.stream.take() {
+            for chunk in stream.chunks {
+                match chunk {
+                    Ok(mut chunk) => self.body.append(&mut chunk),
+                    Err(_e) => {
+                        debug_eprintln!("ERROR: Body stream failed: {_e}");
+                        break;
+                    },
+                }
+            }
+        }
+
+        self.resolve_status();
+        let mut serialized = self.serialize_head(Some(self.body.len()));
 
-        let mut status = match STATUSES.get(self.code) {
+        // output content or not depending on the request method
+        if self.method != "HEAD" {
+            serialized.append(&mut self.body);
+        }
+
+        serialized
+    }
+
+    /// Writes the response to `writer`, returning the amount of bytes written
+    ///
+    /// If a body stream is set, the body is written piece by piece as it is produced, using
+    /// `Transfer-Encoding: chunked` if the length of the stream is not known. HTTP/1.0 clients
+    /// do not understand chunks, so their body ends when the connection is closed, see
+    /// `is_close_delimited`. An error is returned
+    /// if the stream fails or does not match its declared length, in which case the client has
+    /// received an incomplete response and the connection should be closed.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let stream = match self.stream.take() {
+            Some(stream) if !self.resolve_status() => stream,
+            _ => {
+                let data = self.serialize();
+                writer.write_all(&data)?;
+                return Ok(data.len());
+            },
+        };
+
+        let head = self.serialize_head(stream.length);
+        writer.write_all(&head)?;
+        let mut sent = head.len();
+
+        if self.method == "HEAD" {
+            return Ok(sent);
+        }
+
+        let chunked = stream.length.is_none() && self.protocol != "HTTP/1.0";
+        let mut body_sent: usize = 0;
+        for chunk in stream.chunks {
+            let mut chunk = chunk?;
+            if let Some(length) = stream.length {
+                chunk.truncate(length - body_sent);
+            }
+            // an empty chunk would mark the end of the body
+            if chunk.is_empty() {
+                continue;
+            }
+            body_sent += chunk.len();
+
+            if chunked {
+                let size_line = format!("{:X}\r\n", chunk.len());
+                writer.write_all(size_line.as_bytes())?;
+                writer.write_all(&chunk)?;
+                writer.write_all(b"\r\n")?;
+                sent += size_line.len() + chunk.len() + 2;
+            } else {
+                writer.write_all(&chunk)?;
+                sent += chunk.len();
+            }
+        }
+
+        match stream.length {
+            None if chunked => {
+                writer.write_all(b"0\r\n\r\n")?;
+                sent += 5;
+            },
+            None => {},
+            Some(length) if length != body_sent => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    format!("body stream ended after {body_sent} of {length} bytes")));
+            },
+            Some(_) => {},
+        }
+        writer.flush()?;
+
+        Ok(sent)
+    }
+
+    /// Checks that the response code has a status string, replacing the response with a 500 if it
+    /// has none.
+    ///
+    /// Returns true if the response was replaced.
+    pub(crate) fn resolve_status(&mut self) -> bool {
+        let status = match STATUSES.get(self.code) {
             None => self.status,
             Some(thing) => thing,
         };
 
-        if status.is_empty() {
-            debug_eprintln!("ERROR: No default status string for HTTP {}, sending 500", self.code);
-            self.code = "500";
-            status = match STATUSES.get(self.code) {
-                None => "INTERNAL SERVER ERROR",
-                Some(thing) => thing,
-            };
-            self.headers.insert("Content-Type", "text/html".to_string());
-            self.body = format!("<h1>500: {}</h1>", status).into_bytes();
+        if !status.is_empty() {
+            return false;
         }
 
+        debug_eprintln!("ERROR: No default status string for HTTP {}, sending 500", self.code);
+        self.code = "500";
+        let status = match STATUSES.get(self.code) {
+            None => "INTERNAL SERVER ERROR",
+            Some(thing) => thing,
+        };
+        self.headers.insert("Content-Type", "text/html".to_string());
+        self.body = format!("<h1>500: {}</h1>", status).into_bytes();
+        self.stream = None;
+        true
+    }
+
+    /// Adds the headers that are generated when the response is sent, `Set-Cookie` and `Date`
+    pub(crate) fn insert_generated_headers(&mut self) {
         if !self.cookies.is_empty() {
             self.headers.insert("Set-Cookie", self.cookies.iter()
                                 .map(|c| c.to_string())
@@ -146,11 +362,24 @@ impl<'req> Response<'req> {
                                 .reduce(|acc, c| acc + &c).unwrap());
         }
 
-        // emit the status line
-        serialized.append(&mut format!("{} {} {}\r\n", &self.protocol, &self.code, &status).into_bytes());
-
         let now: DateTime<Utc> = Utc::now();
         self.headers.insert("Date", now.format("%a, %d %b %Y %H:%M:%S").to_string());
+    }
+
+    /// Generates the status line and headers, `length` is the length of the body or `None` if it
+    /// is to be sent chunked, or delimited by closing the connection for HTTP/1.0.
+    fn serialize_head(&mut self, length: Option<usize>) -> Vec<u8> {
+        let mut serialized = vec![];
+
+        let status = match STATUSES.get(self.code) {
+            None => self.status,
+            Some(thing) => thing,
+        };
+
+        self.insert_generated_headers();
+
+        // emit the status line
+        serialized.append(&mut format!("{} {} {}\r\n", &self.protocol, &self.code, &status).into_bytes());
 
         // emit headers
         for (key, value) in self.headers.iter() {
@@ -159,17 +388,51 @@ impl<'req> Response<'req> {
             }
         }
 
-        // output content or not depending on the request method
-        if self.method != "HEAD" {
-            serialized.append(&mut format!("Content-Length: {}\r\n\r\n", &self.body.len()).into_bytes());
-            serialized.append(&mut self.body);
-        } else {
-            serialized.append(&mut "Content-Length: 0\r\n\r\n".to_string().into_bytes());
+        // describe the content or not depending on the request method
+        match length {
+            _ if self.method == "HEAD" => {
+                serialized.append(&mut "Content-Length: 0\r\n\r\n".to_string().into_bytes());
+            },
+            Some(length) => {
+                serialized.append(&mut format!("Content-Length: {}\r\n\r\n", length).into_bytes());
+            },
+            None if self.protocol == "HTTP/1.0" => {
+                serialized.append(&mut "\r\n".to_string().into_bytes());
+            },
+            None => {
+                serialized.append(&mut "Transfer-Encoding: chunked\r\n\r\n".to_string().into_bytes());
+            },
         }
 
         serialized
     }
 
+    /// Streams the body to the client from an iterator of chunks instead of sending `body`.
+    ///
+    /// The response is sent with `Transfer-Encoding: chunked` unless `length` is known.
+    pub fn stream_body<I>(&mut self, chunks: I, length: Option<usize>)
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: 'req,
+    {
+        self.stream = Some(BodyStream::new(chunks, length));
+    }
+
+    /// Streams the body to the client from a reader instead of sending `body`.
+    ///
+    /// The response is sent with `Transfer-Encoding: chunked` unless `length` is known.
+    pub fn stream_reader<R: Read + 'req>(&mut self, reader: R, length: Option<usize>) {
+        self.stream = Some(BodyStream::from_reader(reader, length));
+    }
+
+    /// Returns true if the end of the body can only be signalled by closing the connection, which
+    /// is the case for streams of unknown length sent to HTTP/1.0 clients.
+    pub fn is_close_delimited(&self) -> bool {
+        self.protocol == "HTTP/1.0"
+            && self.method != "HEAD"
+            && self.stream.as_ref().is_some_and(|stream| stream.length.is_none())
+    }
+
     /// looks up headers and returns it
     pub fn header(&self, key: &str) -> Option<&str> {
         match self.headers.get(key) {
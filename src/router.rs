@@ -1,14 +1,275 @@
-
 use std::collections::HashMap;
+use std::error;
+use std::fmt::{self, Display};
+use std::sync::Arc;
 
 use crate::context::Context;
+#[cfg(feature = "tokio")]
+use crate::runtime::{AsyncContext, AsyncHandler, BoxFuture};
+
+/// A request handler, plain functions and closures that capture state are both accepted where
+/// handlers are registered
+pub type Handler = Arc<dyn Fn(&mut Context) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum RouteError {
+    /// `route` clashes with the already registered `existing`, either by being the same route or
+    /// by naming a parameter or catch-all differently in the same place
+    Conflict { route: String, existing: String },
+    /// A catch-all is followed by more segments
+    CatchAllNotLast(String),
+}
+impl Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl error::Error for RouteError {}
+
+/// A piece of a route, static text runs up to and including the slash before a parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Static(&'a str),
+    Param(&'a str),
+    CatchAll(&'a str),
+}
+
+/// Splits a route into static text, `:name` parameters and a final `*name` catch-all
+fn tokenize(route: &str) -> Result<Vec<Token<'_>>, RouteError> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let segments = route.split('/').count();
+    for (n, segment) in route.split('/').enumerate() {
+        let dynamic = match segment.as_bytes().first() {
+            Some(b':') if segment.len() > 1 => Some(Token::Param(&segment[1..])),
+            Some(b'*') if n + 1 == segments => Some(Token::CatchAll(&segment[1..])),
+            Some(b'*') => return Err(RouteError::CatchAllNotLast(route.to_string())),
+            _ => None,
+        };
+        if let Some(token) = dynamic {
+            if start < offset {
+                tokens.push(Token::Static(&route[start..offset]));
+            }
+            tokens.push(token);
+            start = offset + segment.len();
+        }
+        offset += segment.len() + 1;
+    }
+    if start < route.len() {
+        tokens.push(Token::Static(&route[start..]));
+    }
+    Ok(tokens)
+}
+
+/// A route and the handler it was registered with
+struct Leaf<H> {
+    route: String,
+    handler: H,
+}
+
+/// A node of the radix tree of a single method
+///
+/// Static children share no first byte, so at most one of them can match. When several kinds of
+/// child could match, static children are tried first, then the parameter, then the catch-all.
+struct Node<H> {
+    /// Static text matched by this node, empty for the node following a parameter
+    prefix: String,
+    /// The route ending at this node
+    leaf: Option<Leaf<H>>,
+    statics: Vec<Node<H>>,
+    /// The name of the parameter and the node following it
+    param: Option<(String, Box<Node<H>>)>,
+    /// The name of the catch-all and the route ending in it
+    catch_all: Option<(String, Leaf<H>)>,
+}
+
+/// A registered route that matched a request document
+pub struct RouteMatch<'r, 'd, H> {
+    /// The route as it was registered
+    pub route: &'r str,
+    pub handler: &'r H,
+    /// The values captured by the parameters of the route, by name
+    pub params: Vec<(&'r str, &'d str)>,
+}
+
+impl<H> Node<H> {
+    fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            leaf: None,
+            statics: Vec::new(),
+            param: None,
+            catch_all: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.leaf.is_none() && self.statics.is_empty() && self.param.is_none() && self.catch_all.is_none()
+    }
+
+    /// Any route registered at or below this node, to describe a conflict with
+    fn any_route(&self) -> Option<&str> {
+        self.leaf.as_ref().map(|leaf| leaf.route.as_str())
+            .or_else(|| self.catch_all.as_ref().map(|(_name, leaf)| leaf.route.as_str()))
+            .or_else(|| self.statics.iter().find_map(Node::any_route))
+            .or_else(|| self.param.as_ref().and_then(|(_name, node)| node.any_route()))
+    }
+
+    /// Adds a route below this node, `tokens` are what is left of it after this node's prefix
+    fn insert(&mut self, tokens: &[Token], route: &str, handler: H) -> Result<(), RouteError> {
+        let conflict = |existing: Option<&str>| RouteError::Conflict {
+            route: route.to_string(),
+            existing: existing.unwrap_or_default().to_string(),
+        };
+
+        match tokens.split_first() {
+            None => match &self.leaf {
+                Some(leaf) => Err(conflict(Some(&leaf.route))),
+                None => {
+                    self.leaf = Some(Leaf { route: route.to_string(), handler });
+                    Ok(())
+                },
+            },
+            Some((Token::Static(text), rest)) => self.insert_static(text, rest, route, handler),
+            Some((Token::Param(name), rest)) => match &mut self.param {
+                Some((existing, node)) if existing != name => Err(conflict(node.any_route())),
+                Some((_name, node)) => node.insert(rest, route, handler),
+                None => {
+                    let mut node = Box::new(Node::new(""));
+                    node.insert(rest, route, handler)?;
+                    self.param = Some((name.to_string(), node));
+                    Ok(())
+                },
+            },
+            Some((Token::CatchAll(name), _)) => match &self.catch_all {
+                Some((_name, leaf)) => Err(conflict(Some(&leaf.route))),
+                None => {
+                    self.catch_all = Some((name.to_string(), Leaf { route: route.to_string(), handler }));
+                    Ok(())
+                },
+            },
+        }
+    }
 
-pub type Handler = fn(&mut Context);
+    /// Adds static text below this node, splitting a child that only shares part of it
+    fn insert_static(&mut self, text: &str, rest: &[Token], route: &str, handler: H) -> Result<(), RouteError> {
+        let first = text.as_bytes()[0];
+        let child = match self.statics.iter_mut().position(|child| child.prefix.as_bytes()[0] == first) {
+            None => {
+                let mut child = Node::new(text);
+                child.insert(rest, route, handler)?;
+                self.statics.push(child);
+                return Ok(());
+            },
+            Some(position) => &mut self.statics[position],
+        };
+
+        let common = child.prefix.bytes()
+            .zip(text.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if common < child.prefix.len() {
+            // the child keeps the shared part, what it held moves into a new node below it
+            let mut split = Node::new(&child.prefix[common..]);
+            split.leaf = child.leaf.take();
+            split.statics = std::mem::take(&mut child.statics);
+            split.param = child.param.take();
+            split.catch_all = child.catch_all.take();
+            child.prefix.truncate(common);
+            child.statics.push(split);
+        }
+        match &text[common..] {
+            "" => child.insert(rest, route, handler),
+            remaining => child.insert_static(remaining, rest, route, handler),
+        }
+    }
+
+    /// Removes a route below this node, returns true if it was registered
+    fn remove(&mut self, tokens: &[Token]) -> bool {
+        let removed = match tokens.split_first() {
+            None => self.leaf.take().is_some(),
+            Some((Token::Static(text), rest)) => self.statics.iter_mut()
+                .find(|child| text.starts_with(child.prefix.as_str()))
+                .is_some_and(|child| match &text[child.prefix.len()..] {
+                    "" => child.remove(rest),
+                    remaining => {
+                        let mut tokens = vec![Token::Static(remaining)];
+                        tokens.extend_from_slice(rest);
+                        child.remove(&tokens)
+                    },
+                }),
+            Some((Token::Param(name), rest)) => match &mut self.param {
+                Some((existing, node)) if existing == name => node.remove(rest),
+                _ => false,
+            },
+            Some((Token::CatchAll(name), _)) => match &self.catch_all {
+                Some((existing, _leaf)) if existing == name => self.catch_all.take().is_some(),
+                _ => false,
+            },
+        };
+
+        // nodes left without routes below them are dropped
+        self.statics.retain(|child| !child.is_empty());
+        if self.param.as_ref().is_some_and(|(_name, node)| node.is_empty()) {
+            self.param = None;
+        }
+        removed
+    }
+
+    /// Finds the route matching `path`, which is what is left of the document after this node's
+    /// prefix, pushing the values of the parameters it passes through
+    fn find<'r, 'd>(&'r self, path: &'d str, params: &mut Vec<(&'r str, &'d str)>) -> Option<&'r Leaf<H>> {
+        if path.is_empty() {
+            if let Some(leaf) = &self.leaf {
+                return Some(leaf);
+            }
+        }
+
+        let first = path.as_bytes().first();
+        if let Some(child) = self.statics.iter().find(|child| child.prefix.as_bytes().first() == first) {
+            if let Some(rest) = path.strip_prefix(child.prefix.as_str()) {
+                if let Some(leaf) = child.find(rest, params) {
+                    return Some(leaf);
+                }
+            }
+        }
+
+        if let Some((name, node)) = &self.param {
+            let end = path.find('/').unwrap_or(path.len());
+            if end > 0 {
+                params.push((name.as_str(), &path[..end]));
+                if let Some(leaf) = node.find(&path[end..], params) {
+                    return Some(leaf);
+                }
+                params.pop();
+            }
+        }
+
+        if let Some((name, leaf)) = &self.catch_all {
+            params.push((name.as_str(), path));
+            return Some(leaf);
+        }
+        None
+    }
+}
 
 /// provides an API to register and lookup HTTP routes
+///
+/// Routes may contain named parameters such as `/users/:id/posts/:post_id`, which match any
+/// single non-empty segment, and may end in a catch-all such as `/assets/*path`, which matches
+/// the rest of the path including further slashes, or nothing after `/assets/`.
+///
+/// Looking from the start of the path, the route with a static segment where others have a
+/// parameter or catch-all wins, then the route with a parameter where others have a catch-all.
+///
+/// The routes of each method are kept in a radix tree, so looking a route up costs about the
+/// same however many routes are registered.
 pub struct Router {
     pub fallback: Handler,
-    routes: HashMap<String, HashMap<String, Handler>>,
+    routes: HashMap<String, Node<Handler>>,
+    #[cfg(feature = "tokio")]
+    async_routes: HashMap<String, Node<AsyncHandler>>,
 }
 
 fn not_implemented(ctx: &mut Context) {
@@ -17,6 +278,42 @@ fn not_implemented(ctx: &mut Context) {
     ctx.response_mut().body = b"<h1>501: Not Implemented</h1>".to_vec();
 }
 
+/// Adds a route to the tree of a method
+fn insert<H>(
+    trees: &mut HashMap<String, Node<H>>,
+    method: &str,
+    route: &str,
+    handler: H,
+) -> Result<(), RouteError> {
+    let tokens = tokenize(route)?;
+    trees.entry(method.to_string())
+        .or_insert_with(|| Node::new(""))
+        .insert(&tokens, route, handler)
+}
+
+/// Looks up the route matching `document` in the tree of a method
+fn find<'r, 'd, H>(
+    trees: &'r HashMap<String, Node<H>>,
+    method: &str,
+    document: &'d str,
+) -> Option<RouteMatch<'r, 'd, H>> {
+    let mut params = Vec::new();
+    let leaf = trees.get(method)?.find(document, &mut params)?;
+    Some(RouteMatch {
+        route: &leaf.route,
+        handler: &leaf.handler,
+        params,
+    })
+}
+
+/// Removes a route from the tree of a method
+fn remove<H>(trees: &mut HashMap<String, Node<H>>, method: &str, route: &str) -> bool {
+    match (trees.get_mut(method), tokenize(route)) {
+        (Some(tree), Ok(tokens)) => tree.remove(&tokens),
+        _ => false,
+    }
+}
+
 impl Default for Router {
     fn default() -> Self {
         Self::new()
@@ -27,59 +324,74 @@ impl Router {
     /// Creates a new router
     pub fn new() -> Self {
         Self {
-            fallback: not_implemented,
+            fallback: Arc::new(not_implemented),
             routes: HashMap::new(),
+            #[cfg(feature = "tokio")]
+            async_routes: HashMap::new(),
         }
     }
 
     /// register a path with a function callback
     /// if a request document path matches the callback path, the callback is fired.
-    pub fn register(&mut self, method: &str, route: &str, func: Handler) -> bool {
-        if !self.routes.contains_key(method) {
-            self.routes.insert(method.to_string(), HashMap::new());
-        }
-        match self.routes.get_mut(method) {
-            None => return false,
-            Some(inner) => inner,
-        }.insert(route.to_string(), func);
-        true
+    /// fails if the path clashes with one already registered for the method.
+    pub fn register<F>(&mut self, method: &str, route: &str, func: F) -> Result<(), RouteError>
+    where F: Fn(&mut Context) + Send + Sync + 'static {
+        insert(&mut self.routes, method, route, Arc::new(func))
+    }
+
+    /// register a path with an async function callback, which is only served by `listen_async`
+    #[cfg(feature = "tokio")]
+    pub fn register_async<F>(&mut self, method: &str, route: &str, func: F) -> Result<(), RouteError>
+    where F: for<'a> Fn(&'a mut AsyncContext) -> BoxFuture<'a> + Send + Sync + 'static {
+        insert(&mut self.async_routes, method, route, Arc::new(func))
+    }
+
+    /// looks up the callback registered for a path, along with the parameters it captured
+    pub fn find<'r, 'd>(&'r self, method: &str, document: &'d str) -> Option<RouteMatch<'r, 'd, Handler>> {
+        find(&self.routes, method, document)
+    }
+
+    /// looks up the async callback registered for a path, along with the parameters it captured
+    #[cfg(feature = "tokio")]
+    pub fn find_async<'r, 'd>(&'r self, method: &str, document: &'d str) -> Option<RouteMatch<'r, 'd, AsyncHandler>> {
+        find(&self.async_routes, method, document)
+    }
+
+    /// returns the registered route that a request for `document` is handled by, `None` if it
+    /// goes to the fallback
+    pub fn route(&self, method: &str, document: &str) -> Option<&str> {
+        self.find(method, document).map(|found| found.route)
     }
 
     /// removes a registered path
     pub fn unregister(&mut self, method: &str, route: &str) -> bool {
-        match self.routes.get_mut(method) {
-            None => false,
-            Some(inner) => {
-                inner.remove(route).is_some()
-            },
-        }
+        #[cfg(feature = "tokio")]
+        let removed_async = remove(&mut self.async_routes, method, route);
+        #[cfg(not(feature = "tokio"))]
+        let removed_async = false;
+
+        remove(&mut self.routes, method, route) || removed_async
     }
 
     /// tries to call a registered path
     /// if it fails, the fallback is automatically called.
     /// if response is already a redirect, don't call.
-    pub fn call(&self, ctx: &mut Context) {
+    pub fn call<'req>(&'req self, ctx: &mut Context<'req>) {
         let method = ctx.request().method;
         let document = ctx.request().document;
 
         if ctx.response().is_redirect() {
             return;
         }
-        let by_method = match self.routes.get(method) {
-            None => {
-                (self.fallback)(ctx);
-                return;
-            },
-            Some(inner) => inner,
-        };
-        let func = match by_method.get(document) {
+        let found = match self.find(method, document) {
             None => {
                 (self.fallback)(ctx);
                 return;
             },
             Some(inner) => inner,
         };
-        func(ctx);
+        ctx.route = Some(found.route);
+        ctx.params = found.params;
+        (found.handler)(ctx);
     }
 }
-
@@ -0,0 +1,499 @@
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+
+use debug_print::debug_eprintln;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::framing::{self, Frame, Framer};
+use crate::recovery::{self, HandlerPanic};
+use crate::request::Request;
+use crate::response::Response;
+use crate::session::SessionManager;
+use crate::state::State;
+use crate::shutdown::ConnectionGuard;
+use crate::transport::{Captured, PeerAddr};
+use crate::{Immortal, ImmortalError, ReadError};
+
+/// The future an async handler returns, which may borrow the context it was given
+pub type BoxFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// An async request handler, registered with `Immortal::register_async`
+///
+/// Plain functions and closures that capture state are both accepted, such as
+/// `move |ctx| Box::pin(async move { ... })`.
+pub type AsyncHandler = Arc<dyn for<'a> Fn(&'a mut AsyncContext) -> BoxFuture<'a> + Send + Sync>;
+
+/// The request and response exposed to async handlers
+///
+/// Unlike `Context` it owns its data, so it can be held across `.await` points. Middleware has
+/// already run by the time a handler gets it, and the response starts out as middleware left it.
+pub struct AsyncContext {
+    pub method: String,
+    pub document: String,
+    pub query_raw: String,
+    /// The request body, empty if none was sent
+    pub body: Vec<u8>,
+    pub peer_addr: Option<PeerAddr>,
+    pub session_id: Uuid,
+    /// The status code of the response
+    pub code: String,
+    /// The headers of the response
+    pub headers: HashMap<String, String>,
+    /// The body of the response
+    pub response_body: Vec<u8>,
+    request_headers: Vec<(String, String)>,
+    session_manager: Arc<SessionManager>,
+    received: Option<Instant>,
+    /// When the handler was given the request
+    dispatched: Instant,
+    /// The route the request matched
+    route: String,
+    /// Values captured by the parameters of the route
+    params: Vec<(String, String)>,
+    state: Arc<State>,
+}
+
+impl AsyncContext {
+    /// Takes a copy of a request and of the response middleware produced for it
+    fn new(
+        request: &Request,
+        response: &mut Response,
+        head: &[u8],
+        session_id: Uuid,
+        session_manager: Arc<SessionManager>,
+        state: Arc<State>,
+    ) -> Self {
+        // renders the cookie of a new session into the headers, the response is rebuilt from them
+        // once the handler is done
+        response.insert_generated_headers();
+
+        Self {
+            method: request.method.to_string(),
+            document: request.document.to_string(),
+            query_raw: request.query_raw.to_string(),
+            body: request.body.unwrap_or_default().to_vec(),
+            peer_addr: request.peer_addr.clone(),
+            session_id,
+            code: response.code.to_string(),
+            headers: response.headers.iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+            response_body: std::mem::take(&mut response.body),
+            request_headers: framing::header_lines(head)
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            session_manager,
+            received: request.received,
+            dispatched: Instant::now(),
+            route: String::new(),
+            params: Vec::new(),
+            state,
+        }
+    }
+
+    /// Reports a panic in the handler and replaces the response with a `500`, like the blocking
+    /// pipeline does
+    fn recover(&mut self, payload: &(dyn Any + Send), immortal: &Immortal) {
+        immortal.report_panic(&HandlerPanic {
+            method: &self.method,
+            document: &self.document,
+            route: Some(self.route.as_str()).filter(|route| !route.is_empty()),
+            message: recovery::message(payload),
+        });
+
+        self.code = "500".to_string();
+        self.headers = immortal.default_headers.iter().cloned().collect();
+        self.headers.insert("Connection".to_string(), "close".to_string());
+        self.response_body = b"<h1>500: Internal Server Error</h1>".to_vec();
+    }
+
+    /// Returns the value captured by the route parameter `name`, see `Context::param`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _value)| key == name)
+            .map(|(_key, value)| value.as_str())
+    }
+
+    /// Returns the value of type `T` managed by the server, see `Context::state`
+    pub fn state<T>(&self) -> &T where T: Send + Sync + 'static {
+        match self.try_state::<T>() {
+            Some(value) => value,
+            None => panic!("no state of type {} is managed", std::any::type_name::<T>()),
+        }
+    }
+
+    /// Returns the value of type `T` managed by the server, if there is one
+    pub fn try_state<T>(&self) -> Option<&T> where T: Send + Sync + 'static {
+        self.state.get::<T>()
+    }
+
+    /// Returns the value of a request header, the name is matched case-insensitively
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.request_headers.iter()
+            .find(|(k, _v)| k.eq_ignore_ascii_case(key))
+            .map(|(_k, v)| v.as_str())
+    }
+
+    /// Makes a write to a session with a key and value, see `Context::write_session`
+    pub fn write_session(&self, session_id: Uuid, key: &str, value: &str) -> bool {
+        if session_id.is_nil() {
+            return false;
+        }
+        self.session_manager.write_session(session_id, key, value)
+    }
+
+    /// Reads from a session store, see `Context::read_session`
+    pub fn read_session(&self, session_id: Uuid, key: &str) -> Option<String> {
+        if session_id.is_nil() {
+            return None;
+        }
+        self.session_manager.read_session(session_id, key)
+    }
+}
+
+/// What became of a request before any async handler ran
+enum Prepared {
+    /// The request was answered by the blocking pipeline, true to keep the connection open
+    Answered(bool),
+    /// The request goes to an async handler
+    Handler(AsyncHandler, Box<AsyncContext>),
+}
+
+impl Immortal {
+    /// Listens for HTTP requests on `socket_addr`, serving each connection as a task on the
+    /// current tokio runtime.
+    ///
+    /// Requests go through the same middleware and router as with `listen`, and to the handlers
+    /// registered with `register_async`. Streamed response bodies are buffered before they are
+    /// sent, and HTTP/2 is not served.
+    pub async fn listen_async<S>(self: Arc<Self>, socket_addr: S) -> Result<(), ImmortalError<'static>> where S: Into<SocketAddr> {
+        let socket_addr = socket_addr.into();
+        let listener = TcpListener::bind(socket_addr).await
+            .map_err(ImmortalError::Io)?;
+
+        println!("Server starting at: http://{socket_addr}");
+
+        self.serve_async(listener).await
+    }
+
+    /// Serves HTTP requests from a tokio listener that was bound elsewhere, see `listen_async`
+    pub async fn serve_async(self: Arc<Self>, listener: TcpListener) -> Result<(), ImmortalError<'static>> {
+        let wake = Arc::new(Notify::new());
+        let waker = wake.clone();
+        self.shutdown.set_waker(Some(Box::new(move || waker.notify_one())));
+
+        let accepted = loop {
+            if self.shutdown.is_shutdown() {
+                break Ok(());
+            }
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted) => continue,
+                    Err(e) => break Err(ImmortalError::AcceptError(e)),
+                },
+                _ = wake.notified() => continue,
+            };
+            if self.at_capacity() {
+                tokio::spawn(shed_connection(stream, self.clone()));
+                continue;
+            }
+
+            let closed = Arc::new(Notify::new());
+            let closer = closed.clone();
+            let guard = self.connections.register(Box::new(move || closer.notify_one()));
+            let immortal = self.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = serve_connection(&immortal, stream, &guard) => {},
+                    _ = closed.notified() => {},
+                }
+            });
+        };
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        loop {
+            self.connections.close_idle();
+            if self.connections.len() == 0 || Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.connections.close_all();
+
+        self.shutdown.set_waker(None);
+        accepted
+    }
+}
+
+/// Answers a connection that arrived while the server was at capacity with
+/// `503 Service Unavailable` and closes it
+async fn shed_connection(mut stream: TcpStream, immortal: Arc<Immortal>) {
+    let mut captured = Captured::new(stream.peer_addr().ok().map(PeerAddr::Tcp));
+    crate::write_unavailable(&mut captured, &immortal);
+    let _ = tokio::time::timeout(Duration::from_secs(1), stream.write_all(&captured.out)).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Serves requests from a connection until either side closes it
+async fn serve_connection(immortal: &Arc<Immortal>, mut stream: TcpStream, guard: &ConnectionGuard) {
+    let mut captured = Captured::new(stream.peer_addr().ok().map(PeerAddr::Tcp));
+    let mut buf: Vec<u8> = Vec::with_capacity(4096);
+    let mut served: usize = 0;
+
+    loop {
+        if immortal.shutdown.is_shutdown() {
+            break;
+        }
+
+        // subsequent requests on a persistent connection may only idle for so long
+        let idle_timeout = match served {
+            0 => immortal.header_read_timeout,
+            _ => immortal.keep_alive_timeout,
+        };
+
+        guard.set_idle(true);
+        let frame = read_frame(&mut stream, &mut buf, idle_timeout, &mut captured, immortal).await;
+        guard.set_idle(false);
+
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                if let ReadError::Framing(_e) = &e {
+                    debug_eprintln!("{}", _e);
+                }
+                crate::write_status(&mut captured, e.status_code(), immortal);
+                send(&mut stream, &mut captured, immortal).await;
+                break;
+            },
+        };
+        served += 1;
+
+        // middleware and blocking handlers may block, so they run off the runtime's workers and
+        // hand the connection's buffers back once done
+        let pipeline = immortal.clone();
+        let prepared = tokio::task::spawn_blocking(move || {
+            let prepared = prepare(&mut captured, &buf, &frame, served, &pipeline);
+            (prepared, captured, buf, frame)
+        }).await;
+        let (prepared, frame) = match prepared {
+            Ok((prepared, returned_captured, returned_buf, frame)) => {
+                captured = returned_captured;
+                buf = returned_buf;
+                (prepared, frame)
+            },
+            Err(_e) => {
+                debug_eprintln!("{}", _e);
+                break;
+            },
+        };
+
+        let keep_alive = match prepared {
+            Prepared::Answered(keep_alive) => keep_alive,
+            Prepared::Handler(handler, mut ctx) => {
+                if let Err(payload) = CatchUnwind(handler(&mut ctx)).await {
+                    ctx.recover(payload.as_ref(), immortal);
+                }
+                respond(&mut captured, &buf, &frame, *ctx, served, immortal)
+            },
+        };
+        buf.drain(..frame.len());
+
+        if !send(&mut stream, &mut captured, immortal).await || !keep_alive {
+            break;
+        }
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Reads from the stream until a complete request is buffered in `buf`, see `crate::read_frame`
+async fn read_frame(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    idle_timeout: Duration,
+    captured: &mut Captured,
+    immortal: &Arc<Immortal>,
+) -> Result<Option<Frame>, ReadError> {
+    let mut chunk = vec![0u8; immortal.read_buffer_size];
+    let mut framer = Framer::default();
+    let mut head_deadline: Option<Instant> = None;
+    let mut body_deadline: Option<Instant> = None;
+    loop {
+        if let Some(frame) = framer.frame(buf, &immortal.limits)? {
+            return Ok(Some(frame));
+        }
+
+        // the body deadline is only unset the first time round with a complete head
+        if let (Some(head_len), None) = (framer.head_len(), body_deadline) {
+            if !expect_continue(stream, &buf[..head_len], captured, immortal).await {
+                return Ok(None);
+            }
+        }
+
+        let timeout = crate::read_timeout(buf, &framer, idle_timeout, &mut head_deadline, &mut body_deadline, immortal);
+        let read = match timeout.is_zero() {
+            true => None,
+            false => tokio::time::timeout(timeout, stream.read(&mut chunk)).await.ok(),
+        };
+        match read {
+            None => return if buf.is_empty() { Ok(None) } else { Err(ReadError::TimedOut) },
+            Some(Ok(0)) => return Ok(None),
+            Some(Ok(sz)) => buf.extend_from_slice(&chunk[..sz]),
+            Some(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Some(Err(_e)) => {
+                debug_eprintln!("{}", _e);
+                return Ok(None);
+            },
+        }
+    }
+}
+
+/// Answers a request that sent an `Expect` header once its head has arrived, see
+/// `crate::answer_expectation`
+///
+/// Header middleware may block, so it runs off the runtime's workers. Returns false if a final
+/// response was sent, or the client could not be written to.
+async fn expect_continue(stream: &mut TcpStream, head: &[u8], captured: &mut Captured, immortal: &Arc<Immortal>) -> bool {
+    if !framing::header_lines(head).any(|(key, _)| key.eq_ignore_ascii_case("Expect")) {
+        return true;
+    }
+
+    let head = head.to_vec();
+    let mut answer = Captured::new(captured.peer_addr.clone());
+    let pipeline = immortal.clone();
+    let answered = tokio::task::spawn_blocking(move || {
+        let peer_addr = answer.peer_addr.clone();
+        let proceed = crate::answer_expectation(&mut answer, &head, peer_addr.as_ref(), None, &pipeline);
+        (proceed, answer.out)
+    }).await;
+    let proceed = match answered {
+        Ok((proceed, mut out)) => {
+            captured.out.append(&mut out);
+            proceed
+        },
+        Err(_e) => {
+            debug_eprintln!("{}", _e);
+            return false;
+        },
+    };
+    send(stream, captured, immortal).await && proceed
+}
+
+/// Sends whatever the pipeline has written so far, returns false if the client could not be
+/// written to in time
+async fn send(stream: &mut TcpStream, captured: &mut Captured, immortal: &Immortal) -> bool {
+    if captured.out.is_empty() {
+        return true;
+    }
+    let out = std::mem::take(&mut captured.out);
+    matches!(tokio::time::timeout(immortal.write_timeout, stream.write_all(&out)).await, Ok(Ok(())))
+}
+
+/// Runs a request through the middleware and hands it to its async handler, requests without one
+/// are answered by the blocking pipeline
+fn prepare(captured: &mut Captured, buf: &[u8], frame: &Frame, served: usize, immortal: &Immortal) -> Prepared {
+    let peer_addr = captured.peer_addr.clone();
+    let request = match crate::frame_request(buf, frame, peer_addr.as_ref()) {
+        Ok(request) => request,
+        // malformed requests are answered the usual way
+        Err(_) => return Prepared::Answered(crate::serve_request(captured, buf, frame, peer_addr.as_ref(), None, served, immortal)),
+    };
+    let found = match immortal.router.find_async(request.method, request.document) {
+        Some(found) => found,
+        None => return Prepared::Answered(crate::serve_request(captured, buf, frame, peer_addr.as_ref(), None, served, immortal)),
+    };
+
+    let params: Vec<(String, String)> = found.params.iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    let mut session_id = Uuid::nil();
+    let (request_rc, response_rc, _) = crate::run_handlers(request, immortal, |ctx| {
+        immortal.middleware.run(ctx);
+        session_id = ctx.session_id;
+    });
+    if response_rc.borrow().is_redirect() {
+        return Prepared::Answered(crate::finish_response(captured, request_rc, response_rc, served, immortal));
+    }
+
+    let mut ctx = Box::new(AsyncContext::new(
+        &request_rc.borrow(),
+        &mut response_rc.borrow_mut(),
+        &buf[..frame.head_len],
+        session_id,
+        immortal.session_manager.clone(),
+        immortal.state.clone(),
+    ));
+    ctx.route = found.route.to_string();
+    ctx.params = params;
+    Prepared::Handler(found.handler.clone(), ctx)
+}
+
+/// Polls the future of an async handler, resolving to the payload of a panic if it panics
+struct CatchUnwind<'a>(BoxFuture<'a>);
+
+impl Future for CatchUnwind<'_> {
+    type Output = Result<(), Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(())) => Poll::Ready(Ok(())),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Writes the response an async handler produced, returns true to keep the connection open
+fn respond(captured: &mut Captured, buf: &[u8], frame: &Frame, ctx: AsyncContext, served: usize, immortal: &Immortal) -> bool {
+    let peer_addr = captured.peer_addr.clone();
+    // parsed fine before the handler ran
+    let mut request = match crate::frame_request(buf, frame, peer_addr.as_ref()) {
+        Ok(request) => request,
+        Err(_) => return false,
+    };
+    request.received = ctx.received;
+    request.session_id = ctx.session_id;
+    if let Some(metrics) = &immortal.metrics {
+        metrics.observe(request.method, &ctx.route, &ctx.code, ctx.dispatched.elapsed());
+    }
+
+    let response = Response {
+        body: ctx.response_body,
+        stream: None,
+        code: &ctx.code,
+        status: "",
+        protocol: match request.version {
+            "1.0" => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        },
+        method: request.method,
+        headers: ctx.headers.iter()
+            .map(|(key, value)| (key.as_str(), value.clone()))
+            .collect(),
+        cookies: Vec::new(),
+    };
+
+    crate::finish_response(
+        captured,
+        Rc::new(RefCell::new(request)),
+        Rc::new(RefCell::new(response)),
+        served,
+        immortal,
+    )
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::time::{Instant, Duration};
 use std::sync::Arc;
 use std::sync::atomic::Ordering::Relaxed;
@@ -59,6 +59,8 @@ pub struct SessionManager {
     prune_rate: AtomicDuration,
     /// When the list was last pruned
     last_prune: AtomicInstant,
+    /// How many sessions pruning has removed
+    pruned: AtomicU64,
 }
 
 impl Default for SessionManager {
@@ -84,6 +86,7 @@ impl SessionManager {
             inactive_duration: AtomicDuration::new(inactive_duration),
             prune_rate: AtomicDuration::new(prune_rate),
             last_prune: AtomicInstant::now(),
+            pruned: AtomicU64::new(0),
         }
     }
 
@@ -92,6 +95,16 @@ impl SessionManager {
         self.is_enabled.load(Relaxed)
     }
 
+    /// returns how many sessions are in the store
+    pub fn session_count(&self) -> usize {
+        self.store.len()
+    }
+
+    /// returns how many sessions have been pruned since the manager was created
+    pub fn pruned_count(&self) -> u64 {
+        self.pruned.load(Relaxed)
+    }
+
     /// enables sessions
     pub fn enable(&self) {
         self.is_enabled.store(true, Relaxed);
@@ -305,6 +318,7 @@ impl SessionManager {
                 .collect::<Vec<Uuid>>();
             let _total = self.store.len();
             to_remove.par_iter().for_each(|id| { self.store.remove(id); });
+            self.pruned.fetch_add(to_remove.len() as u64, Relaxed);
             debug_eprintln!("Pruned {}/{} sessions.", to_remove.len(), _total);
         }
 
@@ -321,6 +335,7 @@ impl SessionManager {
             for id in &to_remove {
                 self.store.remove(id);
             }
+            self.pruned.fetch_add(to_remove.len() as u64, Relaxed);
             debug_eprintln!("Pruned {}/{} sessions.", to_remove.len(), _total);
         }
         self.store.shrink_to_fit();
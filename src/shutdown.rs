@@ -0,0 +1,148 @@
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A handle to stop a listening server from another thread or a signal handler.
+///
+/// Once shut down, the server stops accepting connections, lets in-flight requests finish until
+/// the shutdown timeout passes, and then returns from `listen`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// Unblocks the accept loop of the listening server
+    waker: Mutex<Option<Box<dyn Fn() + Send>>>,
+}
+
+impl ShutdownHandle {
+    /// Creates a new handle that has not been shut down
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the server to shut down, returns immediately
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, SeqCst);
+        if let Some(wake) = self.inner.waker.lock().unwrap().as_ref() {
+            wake();
+        }
+    }
+
+    /// Returns true if a shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(SeqCst)
+    }
+
+    /// Sets the function that unblocks the accept loop of the listening server
+    pub(crate) fn set_waker(&self, waker: Option<Box<dyn Fn() + Send>>) {
+        *self.inner.waker.lock().unwrap() = waker;
+    }
+
+    /// Spawns a thread that shuts the server down when the process receives SIGTERM or SIGINT
+    #[cfg(feature = "signals")]
+    pub fn shutdown_on_signals(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                handle.shutdown();
+            }
+        });
+        Ok(())
+    }
+}
+
+/// An open connection, as tracked by `Connections`
+struct OpenConnection {
+    idle: Arc<AtomicBool>,
+    close: Box<dyn Fn() + Send>,
+}
+
+/// Keeps track of the connections that are currently being served
+#[derive(Default)]
+pub(crate) struct Connections {
+    next_id: AtomicUsize,
+    open: Mutex<HashMap<usize, OpenConnection>>,
+}
+
+impl Connections {
+    /// Tracks a new connection until the returned guard is dropped, `close` must unblock any
+    /// pending reads or writes on the connection.
+    pub(crate) fn register(self: &Arc<Self>, close: Box<dyn Fn() + Send>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Relaxed);
+        let idle = Arc::new(AtomicBool::new(true));
+        self.open.lock().unwrap().insert(id, OpenConnection { idle: idle.clone(), close });
+        ConnectionGuard {
+            connections: self.clone(),
+            id,
+            idle,
+        }
+    }
+
+    /// The amount of connections currently open
+    pub(crate) fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// Closes the connections that are waiting for a request
+    pub(crate) fn close_idle(&self) {
+        for connection in self.open.lock().unwrap().values() {
+            if connection.idle.load(SeqCst) {
+                (connection.close)();
+            }
+        }
+    }
+
+    /// Closes every open connection
+    pub(crate) fn close_all(&self) {
+        for connection in self.open.lock().unwrap().values() {
+            (connection.close)();
+        }
+    }
+
+    /// Waits for busy connections to finish their requests until `timeout` passes, then closes
+    /// any that remain.
+    pub(crate) fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.close_idle();
+            if self.len() == 0 || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.close_all();
+    }
+}
+
+/// Removes a connection from `Connections` when dropped
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: usize,
+    idle: Arc<AtomicBool>,
+}
+
+impl ConnectionGuard {
+    /// Marks the connection as waiting for a request, idle connections are closed straight away
+    /// during a shutdown
+    pub(crate) fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, SeqCst);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,28 @@
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// Application state shared by every handler, holding at most one value of each type
+///
+/// Values are added through `Immortal::manage` and read back through `Context::state`.
+#[derive(Default)]
+pub struct State {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value, replacing any earlier value of the same type
+    pub fn manage<T>(&mut self, value: T) where T: Send + Sync + 'static {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// Returns the value of type `T`, if one is managed
+    pub fn get<T>(&self) -> Option<&T> where T: Send + Sync + 'static {
+        self.values.get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+}
@@ -0,0 +1,215 @@
+
+use std::fmt::{self, Debug, Display};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::error;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::transport::{PeerAddr, Transport};
+
+#[derive(Debug)]
+pub enum TlsError {
+    /// The certificate PEM could not be parsed
+    CertificateInvalid(rustls::pki_types::pem::Error),
+    /// The certificate PEM contains no certificates
+    CertificateMissing,
+    /// The private key PEM could not be parsed
+    PrivateKeyInvalid(rustls::pki_types::pem::Error),
+    /// The private key is not usable with the certificate or the crypto provider
+    Rustls(rustls::Error),
+}
+impl Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl error::Error for TlsError {}
+
+/// A handle to the certificate served by a TLS listener, it can be used to replace the
+/// certificate while the server is running.
+///
+/// New connections use the new certificate, established connections are not affected.
+#[derive(Clone, Default)]
+pub struct CertificateHandle {
+    resolver: Arc<CertificateResolver>,
+}
+
+impl CertificateHandle {
+    /// Creates a new handle with no certificate loaded
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a PEM encoded certificate chain and private key, replacing the current certificate
+    pub fn reload(&self, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), TlsError> {
+        let key = certified_key(cert_pem, key_pem)?;
+        *self.resolver.key.write().unwrap() = Some(key);
+        Ok(())
+    }
+
+    /// Builds the rustls server configuration that serves this handle's certificate
+    pub(crate) fn server_config(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Rustls)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+/// Hands out whatever certificate was loaded most recently
+#[derive(Default)]
+struct CertificateResolver {
+    key: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl Debug for CertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .field("loaded", &self.key.read().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.key.read().unwrap().clone()
+    }
+}
+
+/// Parses a PEM encoded certificate chain and private key into a signing key for rustls
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<CertifiedKey>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::CertificateInvalid)?;
+    if certs.is_empty() {
+        return Err(TlsError::CertificateMissing);
+    }
+
+    let key = PrivateKeyDer::from_pem_slice(key_pem)
+        .map_err(TlsError::PrivateKeyInvalid)?;
+    let key = ring::default_provider()
+        .key_provider
+        .load_private_key(key)
+        .map_err(TlsError::Rustls)?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match()
+        .map_err(TlsError::Rustls)?;
+    Ok(Arc::new(certified))
+}
+
+/// A TCP stream that has completed a TLS handshake
+pub struct TlsStream {
+    inner: StreamOwned<ServerConnection, TcpStream>,
+}
+
+impl TlsStream {
+    /// Performs the server side of the TLS handshake over `stream`, giving up with
+    /// `ErrorKind::TimedOut` if the whole handshake takes longer than `timeout`
+    pub fn accept(mut stream: TcpStream, config: Arc<ServerConfig>, timeout: Duration) -> io::Result<Self> {
+        let mut connection = ServerConnection::new(config)
+            .map_err(io::Error::other)?;
+
+        let mut handshake = Deadline {
+            stream: &mut stream,
+            deadline: Instant::now() + timeout,
+        };
+        while connection.is_handshaking() {
+            connection.complete_io(&mut handshake)?;
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+
+        Ok(Self {
+            inner: StreamOwned::new(connection, stream),
+        })
+    }
+}
+
+/// A stream whose reads and writes all have to finish before a shared deadline, so that a client
+/// trickling in bytes cannot stretch out the handshake
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline<'_> {
+    /// Limits the next read and write to the time left
+    fn arm(&self) -> io::Result<()> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.set_write_timeout(Some(remaining))
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        self.inner.sock.peer_addr().ok().map(PeerAddr::Tcp)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.sock.set_write_timeout(timeout)
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.inner.conn.server_name()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.conn.send_close_notify();
+        let _ = self.inner.flush();
+        let _ = self.inner.sock.shutdown(std::net::Shutdown::Both);
+    }
+}
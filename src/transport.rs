@@ -0,0 +1,382 @@
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+#[cfg(unix)]
+use std::{env, fs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+/// The address of the client on the other end of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    /// A client connected over TCP
+    Tcp(SocketAddr),
+    /// A client connected over a Unix domain socket, along with the credentials of its process
+    /// if the platform provides them
+    Unix(Option<PeerCredentials>),
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+            PeerAddr::Unix(Some(cred)) => match cred.pid {
+                None => write!(f, "unix:{}:{}", cred.uid, cred.gid),
+                Some(pid) => write!(f, "unix:{}:{}:{}", cred.uid, cred.gid, pid),
+            },
+        }
+    }
+}
+
+/// The user, group and process of a client connected over a Unix domain socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// A bidirectional byte stream that HTTP requests can be served over
+///
+/// Everything beyond reading and writing is optional, the defaults describe a stream that has
+/// no known peer, cannot time out and needs no special handling to be closed.
+pub trait Transport: Read + Write {
+    /// The address of the remote peer, if known
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        None
+    }
+
+    /// Sets how long a read may block for, `None` blocks indefinitely
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets how long a write may block for, `None` blocks indefinitely
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The server name the client asked for during a TLS handshake
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Closes the stream once all requests have been served
+    fn shutdown(&mut self) {}
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        TcpStream::peer_addr(self).ok().map(PeerAddr::Tcp)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+/// Wraps any byte stream so it can be served, such as an in-memory pipe or a stream from another
+/// TLS library
+///
+/// The wrapped stream has no known peer and cannot time out, implement `Transport` directly for
+/// those.
+#[derive(Debug)]
+pub struct Plain<S>(pub S);
+
+impl<S: Read> Read for Plain<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Write> Write for Plain<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: Read + Write> Transport for Plain<S> {}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        Some(PeerAddr::Unix(peer_credentials(self.as_raw_fd())))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = UnixStream::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+/// Collects what the request pipeline writes instead of sending it, for connections that are
+/// written to without blocking
+#[cfg(any(feature = "tokio", all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c"))))]
+pub(crate) struct Captured {
+    pub(crate) peer_addr: Option<PeerAddr>,
+    /// Everything written so far
+    pub(crate) out: Vec<u8>,
+}
+
+#[cfg(any(feature = "tokio", all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c"))))]
+impl Captured {
+    pub(crate) fn new(peer_addr: Option<PeerAddr>) -> Self {
+        Self {
+            peer_addr,
+            out: Vec::new(),
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio", all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c"))))]
+impl Read for Captured {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(any(feature = "tokio", all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c"))))]
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "tokio", all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c"))))]
+impl Transport for Captured {
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        self.peer_addr.clone()
+    }
+}
+
+/// A bound socket that the server accepts connections from
+pub(crate) trait Listener {
+    type Stream: Transport + Send;
+
+    /// Waits for the next connection
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+
+    /// Returns a function that unblocks a pending `accept_stream` by connecting to the listener
+    fn waker(&self) -> io::Result<Box<dyn Fn() + Send>>;
+
+    /// Returns a function that closes `stream` from another thread, unblocking any pending reads
+    /// or writes on it
+    fn closer(stream: &Self::Stream) -> Box<dyn Fn() + Send>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn waker(&self) -> io::Result<Box<dyn Fn() + Send>> {
+        let mut wake_addr = self.local_addr()?;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr {
+                SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(Box::new(move || {
+            let _ = TcpStream::connect_timeout(&wake_addr, Duration::from_secs(1));
+        }))
+    }
+
+    fn closer(stream: &TcpStream) -> Box<dyn Fn() + Send> {
+        match stream.try_clone() {
+            Ok(stream) => Box::new(move || {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }),
+            Err(_) => Box::new(|| {}),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn waker(&self) -> io::Result<Box<dyn Fn() + Send>> {
+        let wake_path = self.local_addr()?
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unix listener has no path"))?;
+        Ok(Box::new(move || {
+            let _ = UnixStream::connect(&wake_path);
+        }))
+    }
+
+    fn closer(stream: &UnixStream) -> Box<dyn Fn() + Send> {
+        match stream.try_clone() {
+            Ok(stream) => Box::new(move || {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }),
+            Err(_) => Box::new(|| {}),
+        }
+    }
+}
+
+/// Removes a socket file left behind by a server that is no longer running
+///
+/// Fails if the path is not a socket, or if something is still listening on it.
+#[cfg(unix)]
+pub(crate) fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        metadata => metadata?,
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is already being listened on")),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes the listening socket passed in by systemd socket activation, if the process was started
+/// with one
+///
+/// Follows the `LISTEN_PID` and `LISTEN_FDS` protocol, only the first passed socket is used. The
+/// socket is not passed on to child processes. Fails if the passed socket is not a TCP socket.
+///
+/// This takes ownership of the socket, so it must only be called once. The environment is left
+/// untouched since changing it is not thread safe, callers that want to hide the variables from
+/// child processes have to remove them themselves.
+#[cfg(unix)]
+pub fn listener_from_env() -> io::Result<Option<TcpListener>> {
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<usize>().ok());
+    if pid != Some(std::process::id()) || fds.unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+
+    if !is_tcp_socket(LISTEN_FDS_START)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "passed socket is not a TCP socket"));
+    }
+    // SAFETY: fcntl only reads and sets the descriptor flags of the fd
+    let flags = unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the service manager passes its sockets from LISTEN_FDS_START onwards, and the
+    // caller only takes them once
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    Ok(Some(listener))
+}
+
+/// Returns true if `fd` is a stream socket of the IPv4 or IPv6 family, fails if it is not a
+/// socket at all
+#[cfg(unix)]
+fn is_tcp_socket(fd: RawFd) -> io::Result<bool> {
+    let mut sock_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the pointers are valid for the length given, a bad fd is reported as an error
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, (&mut sock_type as *mut libc::c_int).cast(), &mut len)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: an all zero sockaddr_storage is valid
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: the pointers are valid for the length given, a bad fd is reported as an error
+    let ret = unsafe {
+        libc::getsockname(fd, (&mut addr as *mut libc::sockaddr_storage).cast(), &mut len)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let family = addr.ss_family as libc::c_int;
+    Ok(sock_type == libc::SOCK_STREAM && (family == libc::AF_INET || family == libc::AF_INET6))
+}
+
+/// Reads the credentials of the process on the other end of the Unix domain socket `fd`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(fd: RawFd) -> Option<PeerCredentials> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the pointers are valid for the length given, a bad fd is reported as an error
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, (&mut cred as *mut libc::ucred).cast(), &mut len)
+    };
+    if ret != 0 {
+        return None;
+    }
+    Some(PeerCredentials {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+/// Reads the credentials of the process on the other end of the Unix domain socket `fd`, these
+/// platforms do not report its process id
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+fn peer_credentials(fd: RawFd) -> Option<PeerCredentials> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    // SAFETY: the pointers are valid, a bad fd is reported as an error
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return None;
+    }
+    Some(PeerCredentials {
+        uid,
+        gid,
+        pid: None,
+    })
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))))]
+fn peer_credentials(_fd: RawFd) -> Option<PeerCredentials> {
+    None
+}
@@ -0,0 +1,216 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use immortal_http::{Immortal, ShutdownHandle};
+
+    use crate::common::{bind, read_response};
+
+    /// Starts a server on a single threaded tokio runtime in the background, returning its
+    /// address and a handle to stop it along with a receiver signalled once it has stopped
+    fn spawn_server() -> (SocketAddr, ShutdownHandle, mpsc::Receiver<()>) {
+        let (listener, socket_addr) = bind();
+        listener.set_nonblocking(true).unwrap();
+
+        let mut imm = Immortal::new();
+        imm.add_middleware(|ctx| {
+            ctx.response_mut().headers.insert("X-Middleware", "ran".to_string());
+        });
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"Hello, World!".to_vec();
+        }).unwrap();
+        imm.register("GET", "/blocking", |ctx| {
+            thread::sleep(Duration::from_millis(500));
+            ctx.response_mut().body = b"blocked".to_vec();
+        }).unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        imm.register_async("GET", "/hits", move |ctx| {
+            let hits = hits.clone();
+            Box::pin(async move {
+                let count = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                ctx.response_body = count.to_string().into_bytes();
+            })
+        }).unwrap();
+        imm.register_async("GET", "/slow", |ctx| Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            ctx.response_body = b"slow".to_vec();
+        })).unwrap();
+        imm.register_async("POST", "/echo", |ctx| Box::pin(async move {
+            let content_type = ctx.header("content-type").unwrap_or_default().to_string();
+            ctx.headers.insert("Content-Type".to_string(), content_type);
+            ctx.response_body = std::mem::take(&mut ctx.body);
+        })).unwrap();
+        imm.register_async("GET", "/items/:id", |ctx| Box::pin(async move {
+            ctx.response_body = format!("item {}", ctx.param("id").unwrap_or_default()).into_bytes();
+        })).unwrap();
+        imm.register("GET", "/unknown", |ctx| {
+            ctx.response_mut().code = "799";
+        }).unwrap();
+        imm.register_async("GET", "/async-unknown", |ctx| Box::pin(async move {
+            ctx.code = "799".to_string();
+        })).unwrap();
+        imm.register_async("GET", "/panic", |_ctx| Box::pin(async move {
+            tokio::task::yield_now().await;
+            panic!("async handler failed");
+        })).unwrap();
+        let handle = imm.shutdown_handle();
+
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let _ = Arc::new(imm).serve_async(listener).await;
+            });
+            let _ = stopped_tx.send(());
+        });
+        (socket_addr, handle, stopped_rx)
+    }
+
+    #[test]
+    fn test_async_serves_sync_and_async_routes() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("X-Middleware: ran\r\n"));
+        assert!(response.contains("Connection: keep-alive\r\n"));
+        assert!(response.ends_with("Hello, World!"));
+
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nping").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("X-Middleware: ran\r\n"));
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.ends_with("ping"));
+
+        stream.write_all(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 501 "));
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_async_handlers_run_concurrently() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+
+        let start = Instant::now();
+        let clients: Vec<_> = (0..2).map(|_| thread::spawn(move || {
+            let mut stream = TcpStream::connect(socket_addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            read_response(&mut stream)
+        })).collect();
+        for client in clients {
+            assert!(client.join().unwrap().ends_with("slow"));
+        }
+        // both sleeps overlap on the single runtime thread
+        assert!(start.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_async_shutdown() {
+        let (socket_addr, handle, stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("Hello, World!"));
+
+        handle.shutdown();
+        stopped.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(stream.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn test_async_handler_panic_answers_500() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        stream.write_all(b"GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(response.contains("Connection: close\r\n"));
+
+        // the server keeps serving other connections
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("Hello, World!"));
+    }
+
+    #[test]
+    fn test_async_unknown_code_answers_500() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // both pipelines refuse to send a code they have no reason phrase for
+        stream.write_all(b"GET /unknown HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 500 "));
+        stream.write_all(b"GET /async-unknown HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 500 "));
+    }
+
+    #[test]
+    fn test_async_route_parameters() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        stream.write_all(b"GET /items/42 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("item 42"));
+    }
+
+    #[test]
+    fn test_async_blocking_handler_does_not_stall_runtime() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+
+        let blocking = thread::spawn(move || {
+            let mut stream = TcpStream::connect(socket_addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(b"GET /blocking HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            read_response(&mut stream)
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        // served by the single runtime thread while the blocking handler sleeps
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /items/7 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("item 7"));
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        assert!(blocking.join().unwrap().ends_with("blocked"));
+    }
+
+    #[test]
+    fn test_async_handler_captures_state() {
+        let (socket_addr, _handle, _stopped) = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        for expected in ["1", "2"] {
+            stream.write_all(b"GET /hits HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            assert!(read_response(&mut stream).ends_with(expected));
+        }
+    }
+}
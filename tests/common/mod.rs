@@ -0,0 +1,85 @@
+//! Fixtures shared between the integration tests
+//!
+//! Each test binary only uses some of these.
+#![allow(dead_code)]
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use immortal_http::{Immortal, ShutdownHandle};
+
+/// Processes a GET request for `document` in memory and returns the whole response
+pub fn get(imm: &mut Immortal, document: &str) -> String {
+    let request = format!("GET {document} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    String::from_utf8(imm.process_buffer(request.as_bytes())).unwrap()
+}
+
+/// Processes a GET request for `document` in memory and returns the response body
+pub fn get_body(imm: &mut Immortal, document: &str) -> String {
+    let response = get(imm, document);
+    response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string()
+}
+
+/// Binds a listener to an unused port on the loopback interface
+///
+/// The socket is bound up front, probing for the server instead can connect the probe to itself
+/// while the port is still free.
+pub fn bind() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let socket_addr = listener.local_addr().unwrap();
+    (listener, socket_addr)
+}
+
+/// Starts a server with a `GET /` and `POST /echo` route in the background, returning its
+/// address and a handle to stop it along with a receiver signalled once it has stopped
+pub fn spawn_server(configure: fn(&mut Immortal)) -> (SocketAddr, ShutdownHandle, mpsc::Receiver<()>) {
+    let (listener, socket_addr) = bind();
+    let mut imm = Immortal::new();
+    imm.register("GET", "/", |ctx| {
+        ctx.response_mut().body = b"Hello, World!".to_vec();
+    }).unwrap();
+    imm.register("POST", "/echo", |ctx| {
+        let body = ctx.request().body.unwrap_or_default().to_vec();
+        ctx.response_mut().body = body;
+    }).unwrap();
+    configure(&mut imm);
+    let handle = imm.shutdown_handle();
+
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = imm.serve_with(listener, 2);
+        let _ = stopped_tx.send(());
+    });
+    (socket_addr, handle, stopped_rx)
+}
+
+/// Connects to `socket_addr`, giving up on reads after a few seconds
+pub fn connect(socket_addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(socket_addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+/// Reads a single response off the stream using its `Content-Length` header
+pub fn read_response(stream: &mut TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut byte = [0u8; 1];
+    while !data.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).unwrap() == 0 {
+            return String::from_utf8(data).unwrap();
+        }
+        data.push(byte[0]);
+    }
+    let head = String::from_utf8(data.clone()).unwrap();
+    let content_length = head.split("\r\n")
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map(|len| len.parse::<usize>().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0u8; content_length];
+    stream.read_exact(&mut body).unwrap();
+    data.append(&mut body);
+    String::from_utf8(data).unwrap()
+}
@@ -0,0 +1,105 @@
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+
+    use immortal_http::Immortal;
+    use immortal_http::config::{ConfigError, ServerConfig};
+
+    fn response_for(config: ServerConfig) -> String {
+        let mut imm = Immortal::with_config(config);
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"Hello, World!".to_vec();
+        }).unwrap();
+        String::from_utf8(imm.process_buffer(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")).unwrap()
+    }
+
+    #[test]
+    fn test_builder_overrides_defaults() {
+        let config = ServerConfig::builder()
+            .threads(2)
+            .max_body_size(64)
+            .keep_alive_timeout(Duration::from_secs(1))
+            .default_header("content-type", "text/plain")
+            .build();
+        assert_eq!(config.threads, 2);
+        assert_eq!(config.limits.max_body_size, 64);
+        assert_eq!(config.limits.max_header_size, 8 * 1024);
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(1));
+        assert_eq!(config.write_timeout, Duration::from_secs(30));
+        assert_eq!(config.default_headers, vec![("content-type".to_string(), "text/plain".to_string())]);
+    }
+
+    #[test]
+    fn test_with_config_default_headers_and_banner() {
+        let response = response_for(ServerConfig::builder()
+            .default_header("X-Frame-Options", "DENY")
+            .server_banner("immortal")
+            .build());
+        assert!(response.contains("Content-Type: text/html\r\n"));
+        assert!(response.contains("X-Frame-Options: DENY\r\n"));
+        assert!(response.contains("Server: immortal\r\n"));
+
+        let response = response_for(ServerConfig::builder().clear_default_headers().build());
+        assert!(!response.contains("Content-Type"));
+        assert!(!response.contains("Server"));
+        assert!(response.ends_with("Hello, World!"));
+    }
+
+    #[test]
+    fn test_env_loading() {
+        env::set_var("IMMORTAL_THREADS", "3");
+        env::set_var("IMMORTAL_TIMEOUTS_KEEP_ALIVE", "2.5");
+        env::set_var("IMMORTAL_SESSIONS_ENABLED", "true");
+        let config = ServerConfig::builder().env().unwrap().build();
+        assert_eq!(config.threads, 3);
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(2500));
+        assert!(config.sessions);
+
+        env::set_var("IMMORTAL_LIMITS_MAX_BODY_SIZE", "lots");
+        let err = ServerConfig::builder().env().unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { key, .. } if key == "limits.max_body_size"));
+
+        for var in ["IMMORTAL_THREADS", "IMMORTAL_TIMEOUTS_KEEP_ALIVE", "IMMORTAL_SESSIONS_ENABLED", "IMMORTAL_LIMITS_MAX_BODY_SIZE"] {
+            env::remove_var(var);
+        }
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml_loading() {
+        let config = ServerConfig::builder()
+            .toml(r#"
+                threads = 4
+                server_banner = "immortal"
+
+                [limits]
+                max_connections = 16
+
+                [timeouts]
+                write = 5
+                header_read = 0.5
+
+                [headers]
+                X-Frame-Options = "DENY"
+            "#)
+            .unwrap()
+            .threads(8)
+            .build();
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.server_banner.as_deref(), Some("immortal"));
+        assert_eq!(config.max_connections, 16);
+        assert_eq!(config.write_timeout, Duration::from_secs(5));
+        assert_eq!(config.header_read_timeout, Duration::from_millis(500));
+        assert!(config.default_headers.contains(&("X-Frame-Options".to_string(), "DENY".to_string())));
+
+        let err = ServerConfig::builder().toml("[timeouts]\nforever = 1").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownKey(key) if key == "timeouts.forever"));
+        let err = ServerConfig::builder().toml("threads = -1").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { .. }));
+        let err = ServerConfig::builder().toml("threads = [1]").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { .. }));
+        assert!(matches!(ServerConfig::builder().toml("threads ="), Err(ConfigError::Toml(_))));
+    }
+}
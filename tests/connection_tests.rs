@@ -0,0 +1,369 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use immortal_http::{Immortal, ShutdownHandle};
+
+    use crate::common::{bind, read_response, spawn_server};
+
+    /// Returns true once the peer has closed the stream
+    fn is_closed(stream: &mut TcpStream) -> bool {
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        matches!(stream.read(&mut [0u8; 1]), Ok(0) | Err(_))
+    }
+
+    #[test]
+    fn test_keep_alive_serves_many_requests() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        for _ in 0..3 {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let response = read_response(&mut stream);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("Connection: keep-alive\r\n"));
+            assert!(response.ends_with("Hello, World!"));
+        }
+    }
+
+    #[test]
+    fn test_keep_alive_honours_connection_close() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_http_1_0_closes_by_default() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(is_closed(&mut stream));
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: keep-alive\r\n"));
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"GET / HTTP/3.0\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 505 HTTP VERSION NOT SUPPORTED\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_keep_alive_max_requests() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_max_requests_per_connection(2));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: keep-alive\r\n"));
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: close\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_keep_alive_idle_timeout() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_keep_alive_timeout(Duration::from_millis(200)));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: keep-alive\r\n"));
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_body_spanning_many_reads() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        let body = "0123456789".repeat(1000);
+
+        stream.write_all(format!("POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes()).unwrap();
+        stream.write_all(&body.as_bytes()[..4000]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&body.as_bytes()[4000..]).unwrap();
+
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&body));
+    }
+
+    #[test]
+    fn test_body_too_large() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_max_body_size(16));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 32\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 413 PAYLOAD TOO LARGE\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        // all three requests arrive in a single write and are answered in order
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+            POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nsecond\r\n0\r\n\r\n\
+            GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("\r\n\r\nfirst"));
+        assert!(read_response(&mut stream).ends_with("\r\n\r\nsecond"));
+        assert!(read_response(&mut stream).ends_with("\r\n\r\nHello, World!"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream), "HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(b"hello").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_expect_continue_rejected() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| {
+            imm.set_max_body_size(16);
+            imm.add_header_middleware(|ctx| {
+                if ctx.request_mut().header("Authorization").is_none() {
+                    ctx.response_mut().code = "401";
+                }
+            });
+        });
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        assert!(is_closed(&mut stream));
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nAuthorization: yes\r\nExpect: 100-continue\r\nContent-Length: 32\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 413 PAYLOAD TOO LARGE\r\n"));
+        assert!(is_closed(&mut stream));
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nExpect: something-else\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 417 EXPECTATION FAILED\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_head_too_large() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_max_header_size(64));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: {}\r\n\r\n", "a".repeat(64)).as_bytes()).unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_header_read_timeout() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_header_read_timeout(Duration::from_millis(200)));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        // the deadline holds however often the client sends a byte
+        for byte in b"GET / HTTP/1.1\r\nHost: " {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(30));
+        }
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_header_read_timeout_silent_client() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_header_read_timeout(Duration::from_millis(200)));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        thread::sleep(Duration::from_millis(500));
+        assert!(is_closed(&mut stream));
+    }
+
+    #[test]
+    fn test_body_read_timeout() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_body_read_timeout(Duration::from_millis(200)));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 32\r\n\r\nshort").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT\r\n"));
+        assert!(is_closed(&mut stream));
+    }
+
+    // connections that are served one at a time cannot be held open while another is shed
+    #[cfg(any(feature = "threading", all(unix, feature = "event-loop", not(feature = "h2c"))))]
+    #[test]
+    fn test_max_connections_sheds_load() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| imm.set_max_connections(1));
+        // let the connection used to probe the server be closed first
+        thread::sleep(Duration::from_millis(100));
+
+        let mut held = TcpStream::connect(socket_addr).unwrap();
+        held.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut held).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut shed = TcpStream::connect(socket_addr).unwrap();
+        let response = read_response(&mut shed);
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+        assert!(is_closed(&mut shed));
+
+        drop(held);
+        thread::sleep(Duration::from_millis(100));
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_serve_bound_listener() {
+        let (listener, socket_addr) = bind();
+        thread::spawn(move || {
+            let mut imm = Immortal::new();
+            imm.register("GET", "/", |ctx| {
+                ctx.response_mut().body = b"Hello, World!".to_vec();
+            }).unwrap();
+            let _ = imm.serve_with(listener, 2);
+        });
+
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("\r\n\r\nHello, World!"));
+    }
+
+    /// An in-memory connection that replays `input` and collects everything written to it
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_connection_in_memory() {
+        let mut imm = Immortal::new();
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"Hello, World!".to_vec();
+        }).unwrap();
+        let mut pipe = Pipe {
+            input: io::Cursor::new(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec()),
+            output: Vec::new(),
+        };
+
+        imm.serve_connection(&mut pipe);
+
+        let output = String::from_utf8(pipe.output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("Hello, World!HTTP/1.1 501 NOT IMPLEMENTED\r\n"));
+    }
+
+    #[test]
+    fn test_listener_from_env_without_activation() {
+        assert!(immortal_http::transport::listener_from_env().unwrap().is_none());
+
+        // sockets meant for another process are left alone
+        std::env::set_var("LISTEN_PID", "1");
+        std::env::set_var("LISTEN_FDS", "1");
+        assert!(immortal_http::transport::listener_from_env().unwrap().is_none());
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+    }
+
+    /// Starts a server with a slow route and returns its shutdown handle along with the thread it
+    /// is listening on
+    fn spawn_stoppable_server() -> (SocketAddr, ShutdownHandle, JoinHandle<bool>) {
+        let (listener, socket_addr) = bind();
+        let (tx, rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut imm = Immortal::new();
+            imm.register("GET", "/slow", |ctx| {
+                thread::sleep(Duration::from_millis(300));
+                ctx.response_mut().body = b"done".to_vec();
+            }).unwrap();
+            tx.send(imm.shutdown_handle()).unwrap();
+            imm.serve_with(listener, 2).is_ok()
+        });
+        let handle = rx.recv().unwrap();
+        (socket_addr, handle, server)
+    }
+
+    #[test]
+    fn test_shutdown_finishes_in_flight_requests() {
+        let (socket_addr, handle, server) = spawn_stoppable_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+        assert!(server.join().unwrap());
+        assert!(TcpStream::connect(socket_addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_closes_idle_connections() {
+        let (socket_addr, handle, server) = spawn_stoppable_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(server.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(is_closed(&mut stream));
+    }
+}
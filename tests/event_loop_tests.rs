@@ -0,0 +1,99 @@
+#![cfg(all(unix, feature = "event-loop", not(feature = "threading"), not(feature = "h2c")))]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::common::{connect, read_response, spawn_server};
+
+    #[test]
+    fn test_slow_client_does_not_block_others() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+
+        let mut slow = connect(socket_addr);
+        slow.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\npi").unwrap();
+
+        let start = Instant::now();
+        let mut fast = connect(socket_addr);
+        fast.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut fast).ends_with("Hello, World!"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        slow.write_all(b"ng").unwrap();
+        let response = read_response(&mut slow);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("ping"));
+    }
+
+    #[test]
+    fn test_flooding_client_does_not_block_others() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+
+        // a chunked body that never ends, sent as fast as the server takes it
+        let mut flood = connect(socket_addr);
+        flood.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        let flooding = thread::spawn(move || {
+            let chunk = format!("4000\r\n{}\r\n", "a".repeat(0x4000));
+            while flood.write_all(chunk.as_bytes()).is_ok() {}
+            // the unread part of the flood may reset the connection before the answer is read
+            let mut response = Vec::new();
+            let _ = flood.read_to_end(&mut response);
+            response
+        });
+
+        let start = Instant::now();
+        let mut fast = connect(socket_addr);
+        fast.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut fast).ends_with("Hello, World!"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let response = flooding.join().unwrap();
+        assert!(response.is_empty() || response.starts_with(b"HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn test_event_loop_keep_alive_and_pipelining() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|_| {});
+        let mut stream = connect(socket_addr);
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nPOST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping").unwrap();
+        let first = read_response(&mut stream);
+        assert!(first.contains("Connection: keep-alive\r\n"));
+        assert!(first.ends_with("Hello, World!"));
+        assert!(read_response(&mut stream).ends_with("ping"));
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let last = read_response(&mut stream);
+        assert!(last.contains("Connection: close\r\n"));
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_event_loop_header_read_timeout() {
+        let (socket_addr, _handle, _stopped) = spawn_server(|imm| {
+            imm.set_header_read_timeout(Duration::from_millis(200));
+        });
+        let mut stream = connect(socket_addr);
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 408 "));
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_event_loop_shutdown() {
+        let (socket_addr, handle, stopped) = spawn_server(|_| {});
+        let mut stream = connect(socket_addr);
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).ends_with("Hello, World!"));
+
+        handle.shutdown();
+        stopped.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(stream.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+    }
+}
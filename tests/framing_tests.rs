@@ -0,0 +1,161 @@
+
+#[cfg(test)]
+mod tests {
+
+    use immortal_http::framing::*;
+
+    #[test]
+    fn test_frame_incomplete_head() {
+        let limits = Limits::default();
+        assert_eq!(frame(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n", &limits).unwrap(), None);
+    }
+
+    #[test]
+    fn test_frame_without_body() {
+        let limits = Limits::default();
+        let buf = b"\r\nGET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        let frame = frame(buf, &limits).unwrap().unwrap();
+        assert_eq!(frame.head_len, buf.len());
+        assert_eq!(frame.body_len, 0);
+    }
+
+    #[test]
+    fn test_frame_waits_for_body() {
+        let limits = Limits::default();
+        let buf = b"POST / HTTP/1.1\r\ncontent-length: 13\r\n\r\nHello, ";
+        assert_eq!(frame(buf, &limits).unwrap(), None);
+
+        let buf = b"POST / HTTP/1.1\r\ncontent-length: 13\r\n\r\nHello, World!";
+        let frame = frame(buf, &limits).unwrap().unwrap();
+        assert_eq!(frame.body_len, 13);
+        assert_eq!(frame.len(), buf.len());
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let limits = Limits { max_header_size: 48, max_body_size: 4 };
+
+        let buf = b"GET / HTTP/1.1\r\nX-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::HeadTooLarge { limit: 48 })));
+
+        let buf = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::BodyTooLarge { limit: 4, declared: 5 })));
+    }
+
+    #[test]
+    fn test_frame_content_length_invalid() {
+        let limits = Limits::default();
+        let cases: [&[u8]; 3] = [
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1a\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+        ];
+        for buf in cases {
+            assert!(matches!(frame(buf, &limits), Err(FramingError::ContentLengthInvalid)));
+        }
+    }
+
+    #[test]
+    fn test_frame_chunked() {
+        let limits = Limits::default();
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n7;ext=value\r\nHello, \r\n6\r\nWorld!\r\n0\r\nX-Trailer: yes\r\n\r\n";
+        let frame = frame(buf, &limits).unwrap().unwrap();
+        assert_eq!(frame.len(), buf.len());
+        assert_eq!(frame.decoded_body.unwrap(), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_frame_chunked_incomplete() {
+        let limits = Limits::default();
+        let cases: [&[u8]; 4] = [
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nd\r\nHello, ",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nd\r\nHello, World!\r\n0\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nd\r\nHello, World!\r\n0\r\nX-Trailer: yes\r\n",
+        ];
+        for buf in cases {
+            assert_eq!(frame(buf, &limits).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_frame_chunked_invalid() {
+        let limits = Limits { max_header_size: 1024, max_body_size: 8 };
+
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::ContentLengthWithTransferEncoding)));
+
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::TransferEncodingUnsupported)));
+
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::ChunkedMalformed)));
+
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::ChunkedMalformed)));
+
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabcde\r\n5\r\nabcde\r\n0\r\n\r\n";
+        assert!(matches!(frame(buf, &limits), Err(FramingError::BodyTooLarge { limit: 8, declared: 10 })));
+    }
+
+    #[test]
+    fn test_frame_chunk_extension_too_large() {
+        let limits = Limits::default();
+        let extension = "a".repeat(MAX_CHUNK_EXTENSION_SIZE + 1);
+
+        let buf = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\nx\r\n0\r\n\r\n", extension);
+        let err = frame(buf.as_bytes(), &limits).unwrap_err();
+        assert!(matches!(err, FramingError::ChunkExtensionTooLarge { limit: MAX_CHUNK_EXTENSION_SIZE }));
+        assert_eq!(err.status_code(), "413");
+
+        // rejected before the end of the line arrives
+        let buf = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}", extension.repeat(2));
+        assert!(matches!(frame(buf.as_bytes(), &limits), Err(FramingError::ChunkExtensionTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_frame_chunked_overhead_too_large() {
+        let limits = Limits { max_header_size: 1024, max_body_size: 64 };
+
+        // tiny chunks with long extensions take up far more of the buffer than their data
+        let mut buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let chunk = format!("1;{}\r\nx\r\n", "a".repeat(MAX_CHUNK_EXTENSION_SIZE));
+        for _ in 0..4 {
+            buf.extend_from_slice(chunk.as_bytes());
+        }
+        let err = frame(&buf, &limits).unwrap_err();
+        assert!(matches!(err, FramingError::ChunkedTooLarge { .. }));
+        assert_eq!(err.status_code(), "413");
+
+        // the same amount of data in plain tiny chunks fits
+        let mut buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for _ in 0..64 {
+            buf.extend_from_slice(b"1\r\nx\r\n");
+        }
+        buf.extend_from_slice(b"0\r\n\r\n");
+        let frame = frame(&buf, &limits).unwrap().unwrap();
+        assert_eq!(frame.decoded_body.unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_framer_resumes() {
+        let limits = Limits::default();
+        let buf = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n7;ext=value\r\nHello, \r\n6\r\nWorld!\r\n0\r\nX-Trailer: yes\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let request_len = buf.len() - b"GET / HTTP/1.1\r\n\r\n".len();
+
+        // fed a byte at a time, the request is framed as soon as its last byte arrives
+        let mut framer = Framer::default();
+        for end in 1..request_len {
+            assert_eq!(framer.frame(&buf[..end], &limits).unwrap(), None);
+        }
+        assert_eq!(framer.head_len(), Some(47));
+        let frame = framer.frame(&buf[..request_len], &limits).unwrap().unwrap();
+        assert_eq!(frame.len(), request_len);
+        assert_eq!(frame.decoded_body.unwrap(), b"Hello, World!");
+
+        // and then starts over for the next request
+        assert_eq!(framer.head_len(), None);
+        let frame = framer.frame(&buf[request_len..], &limits).unwrap().unwrap();
+        assert_eq!(frame.len(), buf.len() - request_len);
+    }
+}
@@ -0,0 +1,212 @@
+#![cfg(feature = "h2c")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use immortal_http::Immortal;
+    use immortal_http::h2::PREFACE;
+    use immortal_http::hpack::{self, Decoder, Field};
+
+    use crate::common::bind;
+
+    const DATA: u8 = 0x0;
+    const HEADERS: u8 = 0x1;
+    const SETTINGS: u8 = 0x4;
+    const PING: u8 = 0x6;
+    const GOAWAY: u8 = 0x7;
+    const END_STREAM: u8 = 0x1;
+    const END_HEADERS: u8 = 0x4;
+
+    /// Starts a server in the background and returns its address
+    fn spawn_server() -> SocketAddr {
+        let (listener, socket_addr) = bind();
+        thread::spawn(move || {
+            let mut imm = Immortal::new();
+            imm.register("GET", "/", |ctx| {
+                let version = ctx.request().version.to_string();
+                ctx.response_mut().body = format!("Hello, HTTP/{version}!").into_bytes();
+            }).unwrap();
+            imm.register("POST", "/echo", |ctx| {
+                let body = ctx.request().body.unwrap_or_default().to_vec();
+                ctx.response_mut().body = body;
+            }).unwrap();
+            let _ = imm.serve_with(listener, 2);
+        });
+        socket_addr
+    }
+
+    fn write_frame(stream: &mut TcpStream, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// Reads a frame as its type, flags, stream id and payload
+    fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0u8; 9];
+        stream.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        (header[3], header[4], stream_id, payload)
+    }
+
+    /// Connects with prior knowledge and exchanges settings
+    fn connect(socket_addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(PREFACE).unwrap();
+        write_frame(&mut stream, SETTINGS, 0, 0, &[]);
+        stream
+    }
+
+    fn send_request(stream: &mut TcpStream, stream_id: u32, method: &str, path: &str, body: &[u8]) {
+        let fields: [(&[u8], &[u8]); 4] = [
+            (b":method", method.as_bytes()),
+            (b":scheme", b"http"),
+            (b":path", path.as_bytes()),
+            (b":authority", b"localhost"),
+        ];
+        let block = hpack::encode(fields);
+        match body.is_empty() {
+            true => write_frame(stream, HEADERS, END_HEADERS | END_STREAM, stream_id, &block),
+            false => {
+                write_frame(stream, HEADERS, END_HEADERS, stream_id, &block);
+                write_frame(stream, DATA, END_STREAM, stream_id, body);
+            },
+        }
+    }
+
+    /// Reads frames until `count` streams have ended, returning their headers and bodies in the
+    /// order they finished
+    fn read_responses(stream: &mut TcpStream, count: usize) -> Vec<(u32, Vec<Field>, Vec<u8>)> {
+        let mut decoder = Decoder::new();
+        let mut open: Vec<(u32, Vec<Field>, Vec<u8>)> = Vec::new();
+        let mut done = Vec::new();
+        while done.len() < count {
+            let (kind, flags, stream_id, payload) = read_frame(stream);
+            match kind {
+                HEADERS => open.push((stream_id, decoder.decode(&payload).unwrap(), Vec::new())),
+                DATA => open.iter_mut().find(|(id, _, _)| *id == stream_id).unwrap().2.extend(payload),
+                _ => continue,
+            }
+            if flags & END_STREAM != 0 {
+                let idx = open.iter().position(|(id, _, _)| *id == stream_id).unwrap();
+                done.push(open.remove(idx));
+            }
+        }
+        done
+    }
+
+    fn header<'a>(fields: &'a [Field], name: &str) -> Option<&'a [u8]> {
+        fields.iter()
+            .find(|(key, _)| key == name.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+
+    #[test]
+    fn test_hpack_decode_rfc_examples() {
+        // RFC 7541 C.4.1 and C.4.2, Huffman coded requests sharing a dynamic table
+        let mut decoder = Decoder::new();
+        let first = decoder.decode(&[
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ]).unwrap();
+        assert_eq!(header(&first, ":method"), Some(&b"GET"[..]));
+        assert_eq!(header(&first, ":path"), Some(&b"/"[..]));
+        assert_eq!(header(&first, ":authority"), Some(&b"www.example.com"[..]));
+
+        let second = decoder.decode(&[0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf]).unwrap();
+        assert_eq!(header(&second, ":authority"), Some(&b"www.example.com"[..]));
+        assert_eq!(header(&second, "cache-control"), Some(&b"no-cache"[..]));
+    }
+
+    #[test]
+    fn test_hpack_round_trip() {
+        let fields: [(&[u8], &[u8]); 3] = [(b":status", b"200"), (b"content-type", b"text/html"), (b"x-custom", b"value")];
+        let decoded = Decoder::new().decode(&hpack::encode(fields)).unwrap();
+        let expected: Vec<Field> = fields.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+        assert_eq!(decoded, expected);
+
+        let mut limited = Decoder::new();
+        limited.set_max_list_size(40);
+        assert!(limited.decode(&hpack::encode(fields)).is_err());
+    }
+
+    #[test]
+    fn test_h2_prior_knowledge() {
+        let socket_addr = spawn_server();
+        let mut stream = connect(socket_addr);
+
+        send_request(&mut stream, 1, "GET", "/", b"");
+        send_request(&mut stream, 3, "POST", "/echo", b"ping");
+        let mut responses = read_responses(&mut stream, 2);
+        responses.sort_by_key(|(id, _, _)| *id);
+
+        let (_, fields, body) = &responses[0];
+        assert_eq!(header(fields, ":status"), Some(&b"200"[..]));
+        assert_eq!(body, b"Hello, HTTP/2.0!");
+        assert!(header(fields, "connection").is_none());
+
+        let (_, fields, body) = &responses[1];
+        assert_eq!(header(fields, ":status"), Some(&b"200"[..]));
+        assert_eq!(header(fields, "content-length"), Some(&b"4"[..]));
+        assert_eq!(body, b"ping");
+    }
+
+    #[test]
+    fn test_h2_upgrade() {
+        let socket_addr = spawn_server();
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n").unwrap();
+
+        let expected = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        let mut switching = vec![0u8; expected.len()];
+        stream.read_exact(&mut switching).unwrap();
+        assert_eq!(switching, expected);
+
+        stream.write_all(PREFACE).unwrap();
+        write_frame(&mut stream, SETTINGS, 0, 0, &[]);
+        let responses = read_responses(&mut stream, 1);
+        let (stream_id, fields, body) = &responses[0];
+        assert_eq!(*stream_id, 1);
+        assert_eq!(header(fields, ":status"), Some(&b"200"[..]));
+        assert_eq!(body, b"Hello, HTTP/2.0!");
+    }
+
+    #[test]
+    fn test_h2_ping_and_protocol_error() {
+        let socket_addr = spawn_server();
+        let mut stream = connect(socket_addr);
+
+        write_frame(&mut stream, PING, 0, 0, b"12345678");
+        loop {
+            let (kind, flags, _, payload) = read_frame(&mut stream);
+            if kind == PING {
+                assert_eq!(flags, 0x1);
+                assert_eq!(payload, b"12345678");
+                break;
+            }
+        }
+
+        // DATA may not be sent on the connection itself
+        write_frame(&mut stream, DATA, 0, 0, b"x");
+        loop {
+            let (kind, _, _, payload) = read_frame(&mut stream);
+            if kind == GOAWAY {
+                assert_eq!(payload[4..], 0x1u32.to_be_bytes());
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use immortal_http::Immortal;
+    use immortal_http::logger::{AccessLogger, AccessRecord, LogFormat, Logger, RotatingFile};
+    use immortal_http::transport::PeerAddr;
+
+    /// Collects the lines an `AccessLogger` writes
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// An in-memory connection that reads `input` and discards what is written
+    struct Pipe(Cursor<Vec<u8>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serve(imm: &Immortal, input: &[u8]) {
+        imm.serve_connection(Pipe(Cursor::new(input.to_vec())));
+    }
+
+    /// Keeps the status and session of every record it receives
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(String, usize, bool)>>>);
+
+    impl Logger for Recorder {
+        fn log(&self, record: &AccessRecord) {
+            self.0.lock().unwrap().push((record.code.to_string(), record.sent, record.session_id.is_some()));
+        }
+    }
+
+    fn record(peer_addr: &PeerAddr) -> AccessRecord<'_> {
+        AccessRecord {
+            time: Utc.with_ymd_and_hms(2000, 10, 10, 13, 55, 36).unwrap(),
+            duration: Duration::from_micros(1500),
+            peer_addr: Some(peer_addr),
+            method: "GET",
+            document: "/index.html",
+            query: "a=\"b\"",
+            version: "1.1",
+            code: "200",
+            sent: 2326,
+            user_agent: Some("curl/8.0"),
+            referer: None,
+            session_id: None,
+        }
+    }
+
+    #[test]
+    fn test_log_formats() {
+        let peer_addr = PeerAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        let record = record(&peer_addr);
+
+        assert_eq!(LogFormat::Common.format(&record),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html?a=\"b\" HTTP/1.1" 200 2326"#);
+        assert_eq!(LogFormat::Combined.format(&record),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html?a=\"b\" HTTP/1.1" 200 2326 "-" "curl/8.0""#);
+        assert_eq!(LogFormat::Json.format(&record),
+            r#"{"time":"2000-10-10T13:55:36+00:00","peer":"127.0.0.1","method":"GET","document":"/index.html","query":"a=\"b\"","version":"1.1","status":200,"bytes":2326,"duration_us":1500,"user_agent":"curl/8.0","referer":null,"session_id":null}"#);
+    }
+
+    #[test]
+    fn test_custom_logger_and_disabling() {
+        let recorder = Recorder::default();
+        let mut imm = Immortal::new();
+        imm.enable_sessions();
+        imm.set_logger(recorder.clone());
+        serve(&imm, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let records = recorder.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        // the default fallback answers 501
+        assert_eq!(records[0].0, "501");
+        assert!(records[0].1 > 0);
+        assert!(records[0].2);
+
+        imm.disable_logging();
+        serve(&imm, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(recorder.0.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_access_logger_writes_lines() {
+        let output = Shared::default();
+        let mut imm = Immortal::new();
+        imm.set_logger(AccessLogger::to_writer(LogFormat::Json, output.clone()));
+        serve(&imm, b"GET /a HTTP/1.1\r\nHost: localhost\r\nReferer: http://localhost/\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let contents = output.contents();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""document":"/a""#));
+        assert!(lines[0].contains(r#""referer":"http://localhost/""#));
+        assert!(lines[0].contains(r#""peer":null"#));
+        assert!(lines[1].contains(r#""document":"/b""#));
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("immortal-logger-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(&path).unwrap().max_size(10).keep(2);
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use immortal_http::{Immortal, ServerConfig};
+
+    use crate::common::get;
+
+    fn immortal() -> Immortal {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"Hello, World!".to_vec();
+        }).unwrap();
+        imm
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let mut imm = immortal();
+        imm.enable_metrics("/metrics");
+        get(&mut imm, "/");
+        get(&mut imm, "/");
+        get(&mut imm, "/missing");
+        imm.process_buffer(b"BREW / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let response = get(&mut imm, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(response.contains("# TYPE immortal_requests_total counter\n"));
+        assert!(response.contains("immortal_requests_total{method=\"GET\",route=\"/\",status=\"200\"} 2\n"));
+        assert!(response.contains("immortal_requests_total{method=\"GET\",route=\"unmatched\",status=\"501\"} 1\n"));
+        assert!(response.contains("immortal_requests_total{method=\"OTHER\",route=\"unmatched\",status=\"501\"} 1\n"));
+        assert!(response.contains("immortal_request_duration_seconds_bucket{method=\"GET\",route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(response.contains("immortal_request_duration_seconds_count{method=\"GET\",route=\"/\"} 2\n"));
+        assert!(response.contains("immortal_connections_in_flight 0\n"));
+        assert!(response.contains("immortal_sessions_pruned_total 0\n"));
+        // the request for the metrics is recorded once it has been answered
+        assert!(!response.contains("route=\"/metrics\""));
+        assert!(get(&mut imm, "/metrics").contains("immortal_requests_total{method=\"GET\",route=\"/metrics\",status=\"200\"} 1\n"));
+    }
+
+    #[test]
+    fn test_metrics_are_opt_in() {
+        let mut imm = immortal();
+        get(&mut imm, "/");
+        assert!(get(&mut imm, "/metrics").contains(" 501 "));
+        // nothing is recorded until metrics are enabled
+        assert!(imm.metrics().is_none());
+
+        imm.enable_metrics("/metrics");
+        get(&mut imm, "/");
+        imm.disable_metrics();
+        get(&mut imm, "/");
+        assert!(imm.metrics().unwrap().render().contains("immortal_requests_total{method=\"GET\",route=\"/\",status=\"200\"} 2\n"));
+
+        let mut imm = Immortal::with_config(ServerConfig::builder().metrics_path("/stats").build());
+        assert!(get(&mut imm, "/stats").contains("# TYPE immortal_sessions gauge\n"));
+        imm.disable_metrics();
+        assert!(!get(&mut imm, "/stats").contains("immortal_sessions"));
+    }
+
+    #[test]
+    fn test_middleware_guards_metrics() {
+        let mut imm = immortal();
+        imm.enable_metrics("/metrics");
+        imm.add_middleware(|ctx| {
+            if ctx.request().document == "/metrics" && ctx.request_mut().header("Authorization").is_none() {
+                ctx.redirect("/");
+            }
+        });
+        let response = get(&mut imm, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 302 "));
+        assert!(!response.contains("immortal_requests_total"));
+
+        let response = imm.process_buffer(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nAuthorization: yes\r\n\r\n");
+        assert!(String::from_utf8(response).unwrap().contains("immortal_requests_total"));
+    }
+
+    #[test]
+    fn test_session_gauge() {
+        let mut imm = Immortal::with_config(ServerConfig::builder()
+            .sessions(true)
+            .session_prune_rate(Duration::from_secs(3600))
+            .build());
+        imm.disable_logging();
+        imm.enable_metrics("/metrics");
+        // a session is stored once the client sends back the id it was given
+        for id in ["9a3e2e4c-5d7b-4c1e-8f0a-1b2c3d4e5f60", "0f1e2d3c-4b5a-4978-8a6b-5c4d3e2f1a00"] {
+            let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: id={id}\r\n\r\n");
+            imm.process_buffer(request.as_bytes());
+        }
+        assert!(imm.metrics().unwrap().render().contains("immortal_sessions 2\n"));
+    }
+}
@@ -0,0 +1,124 @@
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    use immortal_http::Immortal;
+    use immortal_http::logger::{AccessRecord, Logger};
+
+    /// An in-memory connection that reads `input` and keeps what is written
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Keeps the status of every record it receives
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Logger for Recorder {
+        fn log(&self, record: &AccessRecord) {
+            self.0.lock().unwrap().push(record.code.to_string());
+        }
+    }
+
+    fn immortal() -> Immortal {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"Hello, World!".to_vec();
+        }).unwrap();
+        imm.register("GET", "/panic", |ctx| {
+            // a borrow held while panicking is released by the unwind
+            let _response = ctx.response_mut();
+            panic!("handler failed for {}", "/panic");
+        }).unwrap();
+        imm.register("GET", "/panic/:id", |ctx| {
+            panic!("handler failed for /panic/{}", ctx.param("id").unwrap_or_default());
+        }).unwrap();
+        imm
+    }
+
+    #[test]
+    fn test_handler_panic_answers_500() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut imm = immortal();
+        let hook_reports = reports.clone();
+        imm.set_panic_hook(move |panic| {
+            let route = panic.route.unwrap_or("<none>");
+            hook_reports.lock().unwrap().push(format!("{} {} {} {}", panic.method, route, panic.document, panic.message));
+        });
+
+        let response = String::from_utf8(imm.process_buffer(b"GET /panic/7 HTTP/1.1\r\nHost: localhost\r\n\r\n")).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(response.contains("Content-Type: text/html\r\n"));
+        assert!(response.ends_with("<h1>500: Internal Server Error</h1>"));
+        assert_eq!(*reports.lock().unwrap(), vec!["GET /panic/:id /panic/7 handler failed for /panic/7"]);
+
+        let response = String::from_utf8(imm.process_buffer(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")).unwrap();
+        assert!(response.ends_with("Hello, World!"));
+    }
+
+    #[test]
+    fn test_middleware_panic_answers_500() {
+        let routes = Arc::new(Mutex::new(Vec::new()));
+        let mut imm = immortal();
+        imm.enable_metrics("/metrics");
+        let hook_routes = routes.clone();
+        imm.set_panic_hook(move |panic| {
+            hook_routes.lock().unwrap().push(panic.route.map(|route| route.to_string()));
+        });
+        imm.add_middleware(|ctx| {
+            if ctx.request().document == "/" {
+                panic!("middleware failed");
+            }
+        });
+        let response = String::from_utf8(imm.process_buffer(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(!response.contains("Hello, World!"));
+        assert!(imm.metrics().unwrap().render().contains("immortal_requests_total{method=\"GET\",route=\"unmatched\",status=\"500\"} 1\n"));
+        // no route was matched before the middleware panicked
+        assert_eq!(*routes.lock().unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn test_panic_is_written_and_logged() {
+        let recorder = Recorder::default();
+        let mut imm = immortal();
+        imm.enable_metrics("/metrics");
+        imm.set_panic_hook(|_| {});
+        imm.set_logger(recorder.clone());
+
+        let mut pipe = Pipe {
+            input: Cursor::new(b"GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec()),
+            output: Vec::new(),
+        };
+        imm.serve_connection(&mut pipe);
+
+        // the connection is closed after the panic rather than trusting it any further
+        let output = String::from_utf8(pipe.output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 500 "));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(!output.contains("Hello, World!"));
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["500"]);
+        assert!(imm.metrics().unwrap().render().contains("immortal_requests_total{method=\"GET\",route=\"/panic\",status=\"500\"} 1\n"));
+    }
+}
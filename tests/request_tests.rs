@@ -2,6 +2,8 @@
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     use immortal_http::request::{Request, RequestError};
     use immortal_http::Immortal;
@@ -9,19 +11,26 @@ mod tests {
 
     #[test]
     fn test_middleware_redirects() {
+        let later_ran = Arc::new(AtomicBool::new(false));
         let mut imm = Immortal::new();
         imm.add_middleware(|ctx| {
             ctx.response_mut().code = "302";
             ctx.response_mut().headers.insert("Location", "/".to_string());
         });
-        imm.add_middleware(|_| {
-            assert!(false);
-        });
-        imm.register("GET", "/", |_| {
-            assert!(false);
+        let ran = later_ran.clone();
+        imm.add_middleware(move |_| {
+            ran.store(true, Ordering::SeqCst);
         });
+        let ran = later_ran.clone();
+        imm.register("GET", "/", move |_| {
+            ran.store(true, Ordering::SeqCst);
+        }).unwrap();
         let request_buffer = b"GET / HTTP/1.1".to_vec();
-        imm.process_buffer(&request_buffer);
+        let response = String::from_utf8(imm.process_buffer(&request_buffer)).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 302 "));
+        assert!(response.contains("\r\nLocation: /\r\n"));
+        assert!(!later_ran.load(Ordering::SeqCst));
     }
 
     #[test]
@@ -30,18 +39,16 @@ mod tests {
         imm.enable_sessions();
 
         imm.register("GET", "/", |ctx| {
-            assert!(!ctx.session_id.is_nil());
-            assert!(!ctx.response().cookies.is_empty());
-
-            for cookie in ctx.response().cookies.iter() {
-                if cookie.name == "id" {
-                    let id = cookie.value.parse::<Uuid>().unwrap();
-                    assert_eq!(id, ctx.session_id);
-                }
-            }
-        });
-
-        imm.process_buffer(b"GET / HTTP/1.1");
+            let session_id = ctx.session_id.to_string().into_bytes();
+            ctx.response_mut().body = session_id;
+        }).unwrap();
+
+        let response = String::from_utf8(imm.process_buffer(b"GET / HTTP/1.1")).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let session_id = body.parse::<Uuid>().unwrap();
+        assert!(!session_id.is_nil());
+        assert!(head.contains(&format!("\r\nSet-Cookie: id={session_id};")));
     }
 
     #[test]
@@ -55,6 +62,52 @@ mod tests {
         assert_eq!(request.version, "1.1");
     }
 
+    #[test]
+    fn test_request_http_1_0() {
+        let request = Request::from_slice(b"GET / HTTP/1.0").unwrap();
+        assert_eq!(request.version, "1.0");
+
+        let mut imm = Immortal::new();
+        imm.register("GET", "/", |_| {}).unwrap();
+        let response = imm.process_buffer(b"GET / HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_request_body_stops_at_content_length() {
+        let request = Request::from_slice(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.body, Some(b"hello".as_slice()));
+
+        let request = Request::from_slice(b"GET / HTTP/1.1\r\n\r\nGET /next HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.body, Some(b"".as_slice()));
+
+        let request = Request::from_slice(b"POST / HTTP/1.1\r\nContent-Length: five\r\n\r\nhello");
+        assert!(matches!(request, Err(RequestError::ContentLengthInvalid)));
+    }
+
+    #[test]
+    fn test_process_buffer_pipelined() {
+        let mut imm = Immortal::new();
+        imm.register("GET", "/", |ctx| {
+            ctx.response_mut().body = b"root".to_vec();
+        }).unwrap();
+        imm.register("POST", "/echo", |ctx| {
+            let body = ctx.request().body.unwrap_or_default().to_vec();
+            ctx.response_mut().body = body;
+        }).unwrap();
+
+        let response = imm.process_buffer(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\n");
+        let response = String::from_utf8(response).unwrap();
+        let statuses = response.match_indices("HTTP/1.1 ").map(|(idx, _)| &response[idx..idx + 12]).collect::<Vec<_>>();
+        assert_eq!(statuses, ["HTTP/1.1 200", "HTTP/1.1 200", "HTTP/1.1 501"]);
+        assert!(response.find("abc").unwrap() < response.find("root").unwrap());
+        assert_eq!(response.matches("Connection: close").count(), 1);
+
+        // nothing after a request that closes the connection is answered
+        let response = imm.process_buffer(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(String::from_utf8(response).unwrap().matches("HTTP/1.1 ").count(), 1);
+    }
+
     #[test]
     fn test_request_with_query() {
         let mut buffer = b"".to_vec();
@@ -111,6 +164,42 @@ mod tests {
         assert_eq!(request.post("param_three"), Some("val%20three"));
     }
 
+    #[test]
+    fn test_request_post_chunked() {
+        let mut imm = Immortal::new();
+        imm.register("POST", "/", |ctx| {
+            assert!(ctx.request_mut().is_chunked());
+            assert_eq!(ctx.request().body, Some(b"param_one=val_one&param_two=val_two".as_slice()));
+            assert_eq!(ctx.request_mut().post("param_one"), Some("val_one"));
+            assert_eq!(ctx.request_mut().post("param_two"), Some("val_two"));
+            ctx.response_mut().code = "201";
+        }).unwrap();
+
+        let mut buffer = b"".to_vec();
+        buffer.append(&mut b"POST / HTTP/1.1\r\n".to_vec());
+        buffer.append(&mut b"Host: 127.0.0.1\r\n".to_vec());
+        buffer.append(&mut b"Content-Type: application/x-www-form-urlencoded\r\n".to_vec());
+        buffer.append(&mut b"Transfer-Encoding: chunked\r\n".to_vec());
+        buffer.append(&mut b"\r\n".to_vec());
+        buffer.append(&mut b"11\r\nparam_one=val_one\r\n".to_vec());
+        buffer.append(&mut b"12;ext\r\n&param_two=val_two\r\n".to_vec());
+        buffer.append(&mut b"0\r\n\r\n".to_vec());
+        let response = imm.process_buffer(&buffer);
+        assert!(response.starts_with(b"HTTP/1.1 201 "));
+    }
+
+    #[test]
+    fn test_request_content_length_with_transfer_encoding() {
+        let mut buffer = b"".to_vec();
+        buffer.append(&mut b"POST / HTTP/1.1\r\n".to_vec());
+        buffer.append(&mut b"Content-Length: 5\r\n".to_vec());
+        buffer.append(&mut b"Transfer-Encoding: chunked\r\n".to_vec());
+        buffer.append(&mut b"\r\n".to_vec());
+        buffer.append(&mut b"0\r\n\r\n".to_vec());
+        let request = Request::from_slice(buffer.as_slice());
+        assert!(matches!(request, Err(RequestError::ContentLengthWithTransferEncoding)));
+    }
+
     #[test]
     fn test_request_cookies() {
         let mut buffer = b"".to_vec();
@@ -175,7 +264,7 @@ mod tests {
         buffer = b"GET / HTTPS/1.1".to_vec();
         cases.push(buffer);
 
-        buffer = b"GET / HTTP/1.0".to_vec();
+        buffer = b"GET / HTTP/3.0".to_vec();
         cases.push(buffer);
 
         for buf in cases {
@@ -0,0 +1,96 @@
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use immortal_http::response::Response;
+
+    fn ok_response<'a>() -> Response<'a> {
+        let mut response = Response::bad();
+        response.code = "200";
+        response.headers.clear();
+        response
+    }
+
+    fn body_of(serialized: &[u8]) -> &[u8] {
+        let idx = serialized.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &serialized[idx + 4..]
+    }
+
+    #[test]
+    fn test_response_stream_chunked() {
+        let mut response = ok_response();
+        response.stream_body(vec![Ok(b"Hello, ".to_vec()), Ok(vec![]), Ok(b"World!".to_vec())], None);
+
+        let mut written = Vec::new();
+        let sent = response.write_to(&mut written).unwrap();
+        assert_eq!(sent, written.len());
+
+        let head = String::from_utf8_lossy(&written);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body_of(&written), b"7\r\nHello, \r\n6\r\nWorld!\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_response_stream_http_1_0() {
+        let mut response = ok_response();
+        response.protocol = "HTTP/1.0";
+        response.stream_body(vec![Ok(b"Hello, ".to_vec()), Ok(b"World!".to_vec())], None);
+        assert!(response.is_close_delimited());
+
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+
+        let head = String::from_utf8_lossy(&written);
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body_of(&written), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_response_stream_known_length() {
+        let mut response = ok_response();
+        response.stream_reader(b"Hello, World!".as_slice(), Some(13));
+
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+
+        assert!(String::from_utf8_lossy(&written).contains("Content-Length: 13\r\n"));
+        assert_eq!(body_of(&written), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_response_stream_short() {
+        let mut response = ok_response();
+        response.stream_body(vec![Ok(b"Hello".to_vec())], Some(13));
+        assert!(response.write_to(&mut Vec::new()).is_err());
+
+        let mut response = ok_response();
+        response.stream_body(vec![Ok(b"Hello".to_vec()), Err(io::ErrorKind::BrokenPipe.into())], None);
+        let mut written = Vec::new();
+        assert!(response.write_to(&mut written).is_err());
+        assert!(!written.ends_with(b"0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_response_stream_serialize() {
+        let mut response = ok_response();
+        response.stream_body(vec![Ok(b"Hello, ".to_vec()), Ok(b"World!".to_vec())], None);
+
+        let serialized = response.serialize();
+        assert!(String::from_utf8_lossy(&serialized).contains("Content-Length: 13\r\n"));
+        assert_eq!(body_of(&serialized), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_response_stream_head() {
+        let mut response = ok_response();
+        response.method = "HEAD";
+        response.stream_body(vec![Ok(b"Hello, World!".to_vec())], None);
+
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        assert_eq!(body_of(&written), b"");
+    }
+}
@@ -0,0 +1,172 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use immortal_http::Immortal;
+    use immortal_http::router::{RouteError, Router};
+
+    use crate::common::get_body;
+
+    #[test]
+    fn test_route_parameters() {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.fallback(|ctx| {
+            ctx.response_mut().body = b"fallback".to_vec();
+        });
+        imm.register("GET", "/users/:id/posts/:post_id", |ctx| {
+            let body = format!("{} {}", ctx.param("id").unwrap(), ctx.param("post_id").unwrap());
+            ctx.response_mut().body = body.into_bytes();
+        }).unwrap();
+
+        assert_eq!(get_body(&mut imm, "/users/42/posts/7"), "42 7");
+        assert_eq!(get_body(&mut imm, "/users/42/posts/7?page=2"), "42 7");
+        assert_eq!(get_body(&mut imm, "/users/42/posts"), "fallback");
+        assert_eq!(get_body(&mut imm, "/users//posts/7"), "fallback");
+        assert_eq!(get_body(&mut imm, "/users/42/posts/7/comments"), "fallback");
+
+        assert!(imm.unregister("GET", "/users/:id/posts/:post_id"));
+        assert_eq!(get_body(&mut imm, "/users/42/posts/7"), "fallback");
+    }
+
+    #[test]
+    fn test_route_precedence() {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.register("GET", "/users/:id", |ctx| {
+            ctx.response_mut().body = b"user".to_vec();
+        }).unwrap();
+        imm.register("GET", "/users/me", |ctx| {
+            ctx.response_mut().body = b"me".to_vec();
+        }).unwrap();
+        imm.register("GET", "/a/:x/c", |ctx| {
+            ctx.response_mut().body = b"a/:x/c".to_vec();
+        }).unwrap();
+        imm.register("GET", "/a/b/:y", |ctx| {
+            ctx.response_mut().body = b"a/b/:y".to_vec();
+        }).unwrap();
+
+        assert_eq!(get_body(&mut imm, "/users/me"), "me");
+        assert_eq!(get_body(&mut imm, "/users/you"), "user");
+        assert_eq!(get_body(&mut imm, "/a/b/c"), "a/b/:y");
+        assert_eq!(get_body(&mut imm, "/a/z/c"), "a/:x/c");
+    }
+
+    #[test]
+    fn test_find() {
+        let mut router = Router::new();
+        router.register("GET", "/files/:name", |_| {}).unwrap();
+        let found = router.find("GET", "/files/a.txt").unwrap();
+        assert_eq!(found.route, "/files/:name");
+        assert_eq!(found.params, vec![("name", "a.txt")]);
+        assert!(router.find("POST", "/files/a.txt").is_none());
+        assert_eq!(router.route("GET", "/files/b.txt"), Some("/files/:name"));
+    }
+
+    #[test]
+    fn test_catch_all_routes() {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.fallback(|ctx| {
+            ctx.response_mut().body = b"fallback".to_vec();
+        });
+        imm.register("GET", "/assets/*path", |ctx| {
+            ctx.response_mut().body = format!("assets {}", ctx.param("path").unwrap()).into_bytes();
+        }).unwrap();
+        imm.register("GET", "/assets/:name", |ctx| {
+            ctx.response_mut().body = format!("asset {}", ctx.param("name").unwrap()).into_bytes();
+        }).unwrap();
+        imm.register("GET", "/assets/css/*path", |ctx| {
+            ctx.response_mut().body = format!("css {}", ctx.param("path").unwrap()).into_bytes();
+        }).unwrap();
+        imm.register("GET", "/*rest", |ctx| {
+            ctx.response_mut().body = format!("rest {}", ctx.param("rest").unwrap()).into_bytes();
+        }).unwrap();
+        imm.register("GET", "/api", |ctx| {
+            ctx.response_mut().body = b"api".to_vec();
+        }).unwrap();
+
+        assert_eq!(get_body(&mut imm, "/assets/js/app.js"), "assets js/app.js");
+        assert_eq!(get_body(&mut imm, "/assets/"), "assets ");
+        assert_eq!(get_body(&mut imm, "/assets/logo.png"), "asset logo.png");
+        assert_eq!(get_body(&mut imm, "/assets/css/site/main.css"), "css site/main.css");
+        assert_eq!(get_body(&mut imm, "/api"), "api");
+        assert_eq!(get_body(&mut imm, "/legacy/page.php?id=1"), "rest legacy/page.php");
+        assert_eq!(get_body(&mut imm, "/"), "rest ");
+
+        assert!(matches!(imm.register("GET", "/*path/edit", |_| {}), Err(RouteError::CatchAllNotLast(_))));
+        assert!(imm.unregister("GET", "/*rest"));
+        assert_eq!(get_body(&mut imm, "/legacy/page.php"), "fallback");
+    }
+
+    #[test]
+    fn test_register_conflicts() {
+        let mut router = Router::new();
+        router.register("GET", "/users/:id", |_| {}).unwrap();
+        router.register("GET", "/users/:id/posts", |_| {}).unwrap();
+        router.register("GET", "/files/*path", |_| {}).unwrap();
+        router.register("POST", "/users/:name", |_| {}).unwrap();
+
+        let err = router.register("GET", "/users/:id", |_| {}).unwrap_err();
+        assert!(matches!(err, RouteError::Conflict { ref existing, .. } if existing == "/users/:id"));
+        let err = router.register("GET", "/users/:name/comments", |_| {}).unwrap_err();
+        assert!(matches!(err, RouteError::Conflict { ref route, ref existing }
+            if route == "/users/:name/comments" && existing.starts_with("/users/:id")));
+        let err = router.register("GET", "/files/*rest", |_| {}).unwrap_err();
+        assert!(matches!(err, RouteError::Conflict { ref existing, .. } if existing == "/files/*path"));
+
+        // once removed, the place can be taken by another name
+        assert!(router.unregister("GET", "/users/:id"));
+        assert!(router.unregister("GET", "/users/:id/posts"));
+        assert!(!router.unregister("GET", "/users/:id"));
+        router.register("GET", "/users/:name/comments", |_| {}).unwrap();
+        assert_eq!(router.find("GET", "/users/ada/comments").unwrap().params, vec![("name", "ada")]);
+    }
+
+    #[test]
+    fn test_shared_prefixes() {
+        let mut router = Router::new();
+        for route in ["/", "/user", "/users", "/users/:id", "/usage", "/u/:x/y", "/us"] {
+            router.register("GET", route, |_| {}).unwrap();
+        }
+        for route in ["/", "/user", "/users", "/usage", "/us"] {
+            assert_eq!(router.route("GET", route), Some(route));
+        }
+        assert_eq!(router.route("GET", "/users/7"), Some("/users/:id"));
+        assert_eq!(router.route("GET", "/u/z/y"), Some("/u/:x/y"));
+        assert_eq!(router.route("GET", "/use"), None);
+        assert_eq!(router.route("GET", "/users/"), None);
+
+        assert!(router.unregister("GET", "/user"));
+        assert_eq!(router.route("GET", "/user"), None);
+        assert_eq!(router.route("GET", "/users"), Some("/users"));
+    }
+
+    #[test]
+    fn test_closure_handlers() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let greeting = String::from("Hello");
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+
+        let counter = hits.clone();
+        imm.add_middleware(move |_ctx| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        imm.register("GET", "/greet/:name", move |ctx| {
+            let body = format!("{greeting}, {}", ctx.param("name").unwrap());
+            ctx.response_mut().body = body.into_bytes();
+        }).unwrap();
+        let missing = "missing".to_string();
+        imm.fallback(move |ctx| {
+            ctx.response_mut().body = missing.clone().into_bytes();
+        });
+
+        assert_eq!(get_body(&mut imm, "/greet/ada"), "Hello, ada");
+        assert_eq!(get_body(&mut imm, "/nowhere"), "missing");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,80 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    use immortal_http::{Context, Immortal, Request, Response};
+    use immortal_http::session::SessionManager;
+    use immortal_http::state::State;
+    use uuid::Uuid;
+
+    use crate::common::get;
+
+    struct Greeting(&'static str);
+
+    #[derive(Default)]
+    struct Visits(Mutex<Vec<String>>);
+
+    fn greet(ctx: &mut Context) {
+        let body = format!("{}, {}", ctx.state::<Greeting>().0, ctx.param("name").unwrap_or("you"));
+        ctx.response_mut().body = body.into_bytes();
+    }
+
+    #[test]
+    fn test_managed_state() {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.manage(Greeting("Hello"));
+        imm.manage(Visits::default());
+        imm.add_middleware(|ctx| {
+            let document = ctx.request().document.to_string();
+            ctx.state::<Visits>().0.lock().unwrap().push(document);
+        });
+        imm.register("GET", "/greet/:name", greet).unwrap();
+        imm.register("GET", "/visits", |ctx| {
+            let count = ctx.state::<Visits>().0.lock().unwrap().len();
+            ctx.response_mut().body = count.to_string().into_bytes();
+        }).unwrap();
+
+        assert!(get(&mut imm, "/greet/ada").ends_with("Hello, ada"));
+        assert!(get(&mut imm, "/visits").ends_with("2"));
+
+        imm.manage(Greeting("Howdy"));
+        assert!(get(&mut imm, "/greet/ada").ends_with("Howdy, ada"));
+    }
+
+    #[test]
+    fn test_missing_state() {
+        let mut imm = Immortal::new();
+        imm.disable_logging();
+        imm.set_panic_hook(|_| {});
+        imm.register("GET", "/", |ctx| {
+            let found = ctx.try_state::<Greeting>().is_some();
+            ctx.response_mut().body = found.to_string().into_bytes();
+        }).unwrap();
+        imm.register("GET", "/greet", greet).unwrap();
+
+        assert!(get(&mut imm, "/").ends_with("false"));
+        assert!(get(&mut imm, "/greet").starts_with("HTTP/1.1 500 "));
+    }
+
+    #[test]
+    fn test_handler_with_substitute_state() {
+        let mut state = State::new();
+        state.manage(Greeting("Hi"));
+
+        let buf = b"GET /greet HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Rc::new(RefCell::new(Request::new(buf, None).unwrap()));
+        let session_manager = Arc::new(SessionManager::default());
+        let mut session_id = Uuid::nil();
+        let response = Rc::new(RefCell::new(Response::new(request.clone(), session_manager.clone(), &mut session_id)));
+        let mut ctx = Context::new(request, response.clone(), session_id, session_manager)
+            .with_state(Arc::new(state));
+
+        greet(&mut ctx);
+        assert_eq!(response.borrow().body, b"Hi, you");
+    }
+}
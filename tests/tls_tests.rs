@@ -0,0 +1,123 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use immortal_http::Immortal;
+    use immortal_http::tls::CertificateHandle;
+
+    use rustls::crypto::ring;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use crate::common::bind;
+
+    const CERT_1: &[u8] = include_bytes!("data/cert1.pem");
+    const KEY_1: &[u8] = include_bytes!("data/key1.pem");
+    const CERT_2: &[u8] = include_bytes!("data/cert2.pem");
+    const KEY_2: &[u8] = include_bytes!("data/key2.pem");
+
+    /// Starts a TLS server serving the first certificate
+    fn spawn_tls_server() -> (SocketAddr, CertificateHandle) {
+        spawn_configured_tls_server(|_| {})
+    }
+
+    /// Starts a TLS server serving the first certificate, configured by `configure`
+    fn spawn_configured_tls_server(configure: fn(&mut Immortal)) -> (SocketAddr, CertificateHandle) {
+        let (listener, socket_addr) = bind();
+        let mut imm = Immortal::new();
+        imm.register("GET", "/", |ctx| {
+            let server_name = ctx.request().server_name.unwrap_or("<none>").to_string();
+            ctx.response_mut().body = server_name.into_bytes();
+        }).unwrap();
+        configure(&mut imm);
+        let handle = imm.certificate_handle();
+        thread::spawn(move || {
+            let _ = imm.serve_tls_with(listener, CERT_1, KEY_1, 2);
+        });
+        (socket_addr, handle)
+    }
+
+    /// Makes a single request over TLS, trusting only `trusted_cert`
+    fn get(socket_addr: SocketAddr, trusted_cert: &[u8]) -> Result<String, std::io::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(trusted_cert).unwrap()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(socket_addr)?);
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut response = Vec::new();
+        match stream.read_to_end(&mut response) {
+            Ok(_) => {},
+            // the server closes the socket right after its close_notify
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => {},
+            Err(e) => return Err(e),
+        }
+        Ok(String::from_utf8(response).unwrap())
+    }
+
+    #[test]
+    fn test_tls_request_with_sni() {
+        let (socket_addr, _handle) = spawn_tls_server();
+
+        let response = get(socket_addr, CERT_1).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("localhost"));
+    }
+
+    #[test]
+    fn test_tls_certificate_reload() {
+        let (socket_addr, handle) = spawn_tls_server();
+        assert!(get(socket_addr, CERT_2).is_err());
+
+        handle.reload(CERT_2, KEY_2).unwrap();
+        assert!(get(socket_addr, CERT_2).unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(socket_addr, CERT_1).is_err());
+    }
+
+    #[test]
+    fn test_tls_reload_rejects_mismatched_key() {
+        let handle = CertificateHandle::new();
+        assert!(handle.reload(CERT_1, KEY_2).is_err());
+        assert!(handle.reload(b"", KEY_1).is_err());
+    }
+
+    #[test]
+    fn test_tls_handshake_deadline() {
+        let (socket_addr, _handle) = spawn_configured_tls_server(|imm| {
+            imm.set_header_read_timeout(Duration::from_millis(300));
+        });
+
+        // a client sending a byte of its hello just before each read would time out
+        let mut stream = TcpStream::connect(socket_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let start = Instant::now();
+        let hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03];
+        for byte in hello.iter().cycle().take(40) {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            match stream.read(&mut [0u8; 1]) {
+                Ok(0) => break,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {},
+                _ => break,
+            }
+        }
+        assert!(start.elapsed() < Duration::from_millis(1500));
+    }
+}
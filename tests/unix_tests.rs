@@ -0,0 +1,88 @@
+#![cfg(unix)]
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use immortal_http::Immortal;
+
+    /// A socket path in the temporary directory that no other test uses
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("immortal-{}-{name}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Starts a server on the socket at `path` that answers with the client's peer address
+    fn spawn_unix_server(path: PathBuf, configure: fn(&mut Immortal)) {
+        let socket_path = path.clone();
+        thread::spawn(move || {
+            let mut imm = Immortal::new();
+            imm.register("GET", "/", |ctx| {
+                let peer = ctx.request().peer_addr.as_ref().map(|p| p.to_string()).unwrap_or_default();
+                ctx.response_mut().body = peer.into_bytes();
+            }).unwrap();
+            configure(&mut imm);
+            imm.listen_unix_with(socket_path, 2).unwrap();
+        });
+
+        for _ in 0..100 {
+            if UnixStream::connect(&path).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("server on {} never came up", path.display());
+    }
+
+    fn get(path: &PathBuf) -> String {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_unix_request_peer_credentials() {
+        let path = socket_path("peer");
+        spawn_unix_server(path.clone(), |_| {});
+
+        let response = get(&path);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        // the client is this very process
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert!(body.starts_with("unix:"));
+        assert!(body.ends_with(&format!(":{}", std::process::id())));
+    }
+
+    #[test]
+    fn test_unix_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        spawn_unix_server(path.clone(), |imm| imm.set_unix_socket_mode(0o600));
+        assert!(get(&path).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_unix_refuses_live_or_foreign_path() {
+        let path = socket_path("live");
+        let _listener = UnixListener::bind(&path).unwrap();
+        assert!(Immortal::new().listen_unix_with(&path, 1).is_err());
+
+        let path = socket_path("file");
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(Immortal::new().listen_unix_with(&path, 1).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}